@@ -2,7 +2,8 @@
 //!
 //! When any call to its underlying writer returns a [`BrokenPipe`](std::io::ErrorKind::BrokenPipe)
 //! error, a [`Writer`] terminates the current process with a SIGPIPE signal, or falls back to a
-//! plain exit with code 1.
+//! plain exit with code 1. This includes errors of other kinds that wrap a broken pipe error
+//! somewhere in their payload's source chain, as adapters like encoders sometimes do.
 //!
 //! # Caveats
 //!
@@ -22,6 +23,10 @@
 //!
 //! Non-Unix platforms always fall back to a plain exit.
 //!
+//! Programs that can't tolerate even a momentary change to SIGPIPE handling can install a hook
+//! with [`Writer::with_hook`] that returns [`Action::Exit`], which exits without touching signal
+//! state at all.
+//!
 //! # Why is this useful?
 //!
 //! Within a shell pipeline, it's good form for a process to exit quickly and silently as soon as
@@ -64,6 +69,18 @@
 //! to your crate root or an appropriate parent module, and ensure that your lint settings allow
 //! the module's unsafe code.
 //!
+//! The file's test utilities in `pipecheck::testing` only exist under `cfg(test)` or a `testing`
+//! feature, which your crate likely doesn't declare, and your crate probably won't use all of the
+//! file's API either. To build without warnings, allow the resulting lints on the module:
+//!
+//! ```ignore
+//! #[allow(unknown_lints, dead_code, unexpected_cfgs)]
+//! mod pipecheck;
+//! ```
+//!
+//! Instead of allowing `unexpected_cfgs`, you can declare an empty `testing` feature of your own,
+//! which also lets you enable the test utilities.
+//!
 //! # Further Reading
 //!
 //! For further background on SIGPIPE, Rust's handling of it, and cross-platform portability
@@ -83,4 +100,20 @@
 
 mod pipecheck;
 
-pub use pipecheck::{wrap, Writer};
+pub use pipecheck::{
+    catch_broken_pipe, check, diagnose, error_chain_has_broken_pipe, exit, exit_for_broken_pipe,
+    ignore_sigpipe, install_default_sigpipe, is_broken_pipe, register_flush, run, self_test,
+    set_hook, sigpipe_disposition, spawn_writer, stderr, stderr_locked, stdout, stdout_locked,
+    wrap, Action, BrokenPipeEvent, BrokenPipeExit, BrokenPipePanic, BufWriter, Builder,
+    CheckedLines, DefaultSigpipeGuard, Diagnosis, IoResultExt, LimitWriter, LineWriter,
+    OrderedSink, SigpipeDisposition, StreamInfo, StreamKind, SyncLineWriter, Tee, Termination,
+    WriteExt, WriteStats, Writer, WriterHandle,
+};
+
+#[cfg(any(test, feature = "testing"))]
+pub use pipecheck::testing;
+
+#[cfg(unix)]
+pub use pipecheck::{
+    copy, flush_at_exit, stream_info, watch_for_closure, ClosureWatcher, RawFdWriter,
+};
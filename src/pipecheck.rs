@@ -26,134 +26,3204 @@
 //! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 //! SOFTWARE.
 
+use std::any::Any;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
 use std::io::{self, Write};
+use std::panic;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, Once, TryLockError};
+use std::thread;
+
+#[cfg(unix)]
+use std::{
+    cmp,
+    io::Read,
+    os::unix::io::{AsRawFd, RawFd},
+    time::Duration,
+};
 
 /// A convenient alias for [`Writer::new`].
 pub fn wrap<W: Write>(w: W) -> Writer<W> {
     Writer::new(w)
 }
 
-/// A writer that silently terminates the program on broken pipe errors.
+/// Wraps the standard output stream of the current process, in a writer named `stdout`.
+pub fn stdout() -> Writer<io::Stdout> {
+    Writer::named("stdout", io::stdout())
+}
+
+/// Wraps the standard error stream of the current process, in a writer named `stderr`.
+pub fn stderr() -> Writer<io::Stderr> {
+    Writer::named("stderr", io::stderr())
+}
+
+/// Wraps a lock on the standard output stream that is held for the life of the writer.
+///
+/// This avoids locking the stream on every write in hot output loops, but blocks other users of
+/// the stream (including `println!`) until the writer is dropped. The first call leaks a small
+/// handle to the stream, shared by all later calls, to support Rust versions before 1.61.
+pub fn stdout_locked() -> Writer<io::StdoutLock<'static>> {
+    static INIT: Once = Once::new();
+    static STDOUT: AtomicPtr<io::Stdout> = AtomicPtr::new(ptr::null_mut());
+    Writer::named("stdout", leak_once(&INIT, &STDOUT, io::stdout).lock())
+}
+
+/// Wraps a lock on the standard error stream that is held for the life of the writer.
+///
+/// See [`stdout_locked`] for caveats.
+pub fn stderr_locked() -> Writer<io::StderrLock<'static>> {
+    static INIT: Once = Once::new();
+    static STDERR: AtomicPtr<io::Stderr> = AtomicPtr::new(ptr::null_mut());
+    Writer::named("stderr", leak_once(&INIT, &STDERR, io::stderr).lock())
+}
+
+// Leaks the value from `init` into `slot` on the first call, and returns it on every call.
+fn leak_once<T>(once: &'static Once, slot: &'static AtomicPtr<T>, init: fn() -> T) -> &'static T {
+    once.call_once(|| slot.store(Box::into_raw(Box::new(init())), Ordering::Release));
+    // SAFETY: The pointer comes from Box::into_raw above once `once` completes, and is never freed.
+    unsafe { &*slot.load(Ordering::Acquire) }
+}
+
+/// An extension trait for wrapping writers fluently, as in
+/// `BufWriter::new(io::stdout()).pipecheck()`.
+pub trait WriteExt: Write + Sized {
+    /// Wraps this writer in a [`Writer`].
+    fn pipecheck(self) -> Writer<Self> {
+        Writer::new(self)
+    }
+}
+
+impl<W: Write> WriteExt for W {}
+
+/// An extension trait for applying a default [`Writer`]'s behavior to a single result, as in
+/// `serde_json::to_writer(io::stdout(), &value).or_exit_on_broken_pipe()`.
+///
+/// This covers any result whose error implements [`Error`], including [`io::Result`].
+pub trait IoResultExt: Sized {
+    /// Terminates the process like [`exit_for_broken_pipe`] if this is a broken pipe error
+    /// anywhere in its chain, and otherwise returns it unchanged.
+    fn or_exit_on_broken_pipe(self) -> Self;
+}
+
+impl<T, E> IoResultExt for Result<T, E>
+where
+    E: Error + 'static,
+{
+    fn or_exit_on_broken_pipe(self) -> Self {
+        match self {
+            Err(ref err) if error_chain_has_broken_pipe(err) => exit_for_broken_pipe(),
+            _ => self,
+        }
+    }
+}
+
+/// A writer that silently terminates the program on broken pipe errors.
+///
+/// When any call to its underlying writer returns a [`BrokenPipe`](io::ErrorKind::BrokenPipe)
+/// error, a `Writer` terminates the current process with a SIGPIPE signal, or falls back to a
+/// plain exit with code 1. This includes errors of other kinds that wrap a broken pipe error
+/// somewhere in their payload's source chain, as some adapters surface them.
+///
+/// Use [`Writer::builder`] to customize this behavior.
+pub struct Writer<W>
+where
+    W: Write,
+{
+    inner: W,
+    state: State,
+}
+
+struct State {
+    config: Config,
+    discarding: AtomicBool,
+    writes: AtomicUsize,
+    bytes: AtomicUsize,
+    totals: Option<Arc<Totals>>,
+}
+
+struct Config {
+    name: Option<Cow<'static, str>>,
+    exit_code: i32,
+    signal: Option<i32>,
+    diagnostic: Option<bool>,
+    also_kinds: Vec<io::ErrorKind>,
+    hook: Option<HookFn>,
+}
+
+type Hook = dyn Fn(&BrokenPipeEvent<'_>) -> Action + Send + Sync;
+
+// Boxing the hook would otherwise cost every Writer its UnwindSafe and RefUnwindSafe impls. Like
+// std::panic::set_hook, we don't demand those of the closure itself: pipecheck keeps no state of
+// its own that a panicking hook could leave broken, and catching the panic of a hook whose captures
+// don't allow it is the caller's choice, as with AssertUnwindSafe.
+struct HookFn(Box<Hook>);
+
+impl panic::UnwindSafe for HookFn {}
+impl panic::RefUnwindSafe for HookFn {}
+
+// The default signal to terminate by, which only exists on Unix.
+#[cfg(unix)]
+const SIGPIPE: Option<i32> = Some(libc::SIGPIPE);
+#[cfg(not(unix))]
+const SIGPIPE: Option<i32> = None;
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            name: None,
+            exit_code: 1,
+            signal: SIGPIPE,
+            diagnostic: None,
+            also_kinds: Vec::new(),
+            hook: None,
+        }
+    }
+}
+
+impl<W> Writer<W>
+where
+    W: Write,
+{
+    pub fn new(w: W) -> Writer<W> {
+        Writer::builder(w).build()
+    }
+
+    /// Creates a `Writer` that calls `hook` right before terminating the process.
+    ///
+    /// The hook only runs for broken pipes detected by this particular writer, and its returned
+    /// [`Action`] decides what the writer does next. The hook may write to other streams, e.g. to
+    /// flush a log. Any writer that hits a broken pipe while a hook is running on the same thread
+    /// terminates right away without running its own hook.
+    pub fn with_hook<F>(w: W, hook: F) -> Writer<W>
+    where
+        F: Fn(&BrokenPipeEvent<'_>) -> Action + Send + Sync + 'static,
+    {
+        Writer::builder(w).hook(hook).build()
+    }
+
+    /// Creates a `Writer` with a name that identifies it to hooks and in `Debug` output, as with
+    /// [`Builder::name`].
+    pub fn named<N>(name: N, w: W) -> Writer<W>
+    where
+        N: Into<Cow<'static, str>>,
+    {
+        Writer::builder(w).name(name).build()
+    }
+
+    /// Returns this writer's name, if it has one.
+    pub fn name(&self) -> Option<&str> {
+        self.state.config.name.as_ref().map(|name| &**name)
+    }
+
+    /// Starts configuring a `Writer` that wraps `w`.
+    pub fn builder(w: W) -> Builder<W> {
+        Builder {
+            inner: w,
+            config: Config::default(),
+        }
+    }
+
+    /// Gets a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Gets a mutable reference to the underlying writer.
+    ///
+    /// Writes made directly through this reference aren't checked for broken pipes.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Unwraps this `Writer`, returning the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Replaces this writer's hook in place, e.g. to switch how a long-lived connection handles
+    /// a broken pipe as its protocol moves between phases, without unwrapping the writer.
+    ///
+    /// The hook works as with [`Writer::with_hook`], and takes effect at the next write.
+    pub fn set_hook<F>(&mut self, hook: F)
+    where
+        F: Fn(&BrokenPipeEvent<'_>) -> Action + Send + Sync + 'static,
+    {
+        self.state.config.hook = Some(HookFn(Box::new(hook)));
+    }
+
+    /// Removes this writer's hook, so that broken pipes terminate the process as if it never had
+    /// one.
+    pub fn clear_hook(&mut self) {
+        self.state.config.hook = None;
+    }
+
+    /// Calls `f` with this writer handling broken pipes by `action` instead of its hook, and
+    /// restores the hook afterwards, even if `f` panics.
+    ///
+    /// For example, [`Action::Error`] keeps an optional section of output from being fatal.
+    /// After [`Action::Discard`], the writer keeps discarding its output past the end of `f`.
+    pub fn with_action<F, R>(&mut self, action: Action, f: F) -> R
+    where
+        F: FnOnce(&mut Writer<W>) -> R,
+    {
+        struct Restore<'a, W>
+        where
+            W: Write,
+        {
+            writer: &'a mut Writer<W>,
+            hook: Option<HookFn>,
+        }
+        impl<'a, W> Drop for Restore<'a, W>
+        where
+            W: Write,
+        {
+            fn drop(&mut self) {
+                self.writer.state.config.hook = self.hook.take();
+            }
+        }
+        let hook = self.state.config.hook.take();
+        self.set_hook(move |_| action);
+        let restore = Restore { writer: self, hook };
+        f(restore.writer)
+    }
+
+    /// Returns whether this writer is discarding its output after a hook returned
+    /// [`Action::Discard`] for a broken pipe.
+    pub fn pipe_broken(&self) -> bool {
+        self.state.discarding.load(Ordering::Relaxed)
+    }
+
+    /// Returns statistics about the writes that this writer has passed on successfully.
+    pub fn stats(&self) -> WriteStats {
+        self.state.stats()
+    }
+}
+
+/// A builder for a [`Writer`] with non-default behavior, created by [`Writer::builder`].
+pub struct Builder<W>
+where
+    W: Write,
+{
+    inner: W,
+    config: Config,
+}
+
+impl<W> Builder<W>
+where
+    W: Write,
+{
+    /// Sets the code for the plain exit used outside of Unix, or when termination by SIGPIPE
+    /// is disabled or fails. The default is 1.
+    pub fn exit_code(mut self, code: i32) -> Builder<W> {
+        self.config.exit_code = code;
+        self
+    }
+
+    /// Sets whether to attempt termination by SIGPIPE on Unix before falling back to a plain
+    /// exit. The default is `true`.
+    ///
+    /// When disabled, the `Writer` never touches the signal mask or SIGPIPE disposition.
+    pub fn sigpipe(mut self, enabled: bool) -> Builder<W> {
+        self.config.signal = if enabled { SIGPIPE } else { None };
+        self
+    }
+
+    /// Sets a different signal to attempt termination by on Unix, such as `SIGTERM` for
+    /// supervisors that treat deaths by SIGPIPE as crashes. This takes the place of
+    /// [`sigpipe`](Builder::sigpipe), and any signal whose action can't be reset to the default
+    /// (like `SIGKILL`) falls back to a plain exit.
+    ///
+    /// The termination sequence unblocks and resets the action for this signal instead of
+    /// SIGPIPE.
+    ///
+    /// Only pick a signal whose default action terminates the process. Signals that are ignored
+    /// by default, like `SIGCHLD` or `SIGWINCH`, do nothing when raised, so the `Writer` always
+    /// falls back to a plain exit. Stop signals, like `SIGTSTP`, `SIGTTIN`, or `SIGTTOU`, suspend
+    /// the process instead, which then only falls back to a plain exit once something sends it
+    /// `SIGCONT`.
+    pub fn signal(mut self, signal: i32) -> Builder<W> {
+        self.config.signal = Some(signal);
+        self
+    }
+
+    /// Treats errors of the given kinds exactly like broken pipes, in addition to
+    /// [`BrokenPipe`](io::ErrorKind::BrokenPipe) itself.
+    ///
+    /// For example, a closed downstream socket can surface as
+    /// [`ConnectionReset`](io::ErrorKind::ConnectionReset) or
+    /// [`ConnectionAborted`](io::ErrorKind::ConnectionAborted) on some platforms.
+    pub fn also_terminate_on(mut self, kinds: &[io::ErrorKind]) -> Builder<W> {
+        self.config.also_kinds.extend_from_slice(kinds);
+        self
+    }
+
+    /// Sets a hook to call right before terminating the process, as with [`Writer::with_hook`].
+    pub fn hook<F>(mut self, hook: F) -> Builder<W>
+    where
+        F: Fn(&BrokenPipeEvent<'_>) -> Action + Send + Sync + 'static,
+    {
+        self.config.hook = Some(HookFn(Box::new(hook)));
+        self
+    }
+
+    /// Sets whether to print a one-line note like `grep: broken pipe on stdout after 4123 bytes`
+    /// to stderr before terminating, if stderr is a terminal. Either way, this overrides the
+    /// `PIPECHECK_DIAGNOSTIC` environment variable, which otherwise enables the note when set to
+    /// anything but `0`.
+    ///
+    /// This leaves a breadcrumb for anyone debugging a tool that dies silently in a complex
+    /// pipeline, without cluttering logs. The note is only printed when the `Writer` terminates
+    /// the process directly, per [`Action::Terminate`] or [`Action::Exit`], and never outside of
+    /// Unix, where pipecheck can't tell whether stderr is a terminal.
+    pub fn diagnostic(mut self, enabled: bool) -> Builder<W> {
+        self.config.diagnostic = Some(enabled);
+        self
+    }
+
+    /// Sets a name for the `Writer`, like `"stdout"` or a file path, which hooks receive through
+    /// [`BrokenPipeEvent::name`] so that programs with several streams can tell which one broke.
+    ///
+    /// On Unix, setting the `PIPECHECK_STATS` environment variable to anything but `0` also makes
+    /// the process print a one-line summary like `grep: pipecheck stats: stdout 4123 bytes in 12
+    /// writes (broken)` to stderr when it exits normally, covering every named `Writer` built while
+    /// the variable was set. Writers sharing a name are summed up.
+    pub fn name<N>(mut self, name: N) -> Builder<W>
+    where
+        N: Into<Cow<'static, str>>,
+    {
+        self.config.name = Some(name.into());
+        self
+    }
+
+    /// Creates the configured `Writer`.
+    pub fn build(self) -> Writer<W> {
+        Writer {
+            inner: self.inner,
+            state: State {
+                totals: register_totals(&self.config),
+                config: self.config,
+                discarding: AtomicBool::new(false),
+                writes: AtomicUsize::new(0),
+                bytes: AtomicUsize::new(0),
+            },
+        }
+    }
+}
+
+/// What a [`Writer`] does about a broken pipe, as decided by its hook.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Terminate the process as the writer is configured to, by SIGPIPE or a plain exit.
+    Terminate,
+    /// Exit the process with the given code, without attempting termination by SIGPIPE.
+    ///
+    /// This never touches the signal mask or SIGPIPE disposition, for programs where another
+    /// component owns signal handling.
+    Exit(i32),
+    /// Return the error to the caller of the write instead of terminating the process.
+    ///
+    /// For a writer with a [name](Builder::name), the error gains context like `... while writing
+    /// to stdout`, keeping its kind and the original error as its source.
+    Error,
+    /// Report success for this and every later write while discarding the output, so the
+    /// program can wind down naturally (e.g. to run destructors that commit or clean up state).
+    ///
+    /// [`Writer::pipe_broken`] reports whether this has happened.
+    Discard,
+    /// Unwind the stack up to the nearest [`catch_broken_pipe`], which then terminates the
+    /// process as the writer is configured to, so that `Drop` implementations along the way
+    /// (e.g. restoring a raw mode terminal or releasing file locks) get to run.
+    ///
+    /// This unwinds without invoking the panic hook, so nothing is printed. With `panic = "abort"`
+    /// the process aborts instead. A broken pipe detected while the thread is already unwinding,
+    /// such as from a `Drop` implementation that writes to the same stream, acts like
+    /// [`Action::Terminate`] instead of starting a second panic that would abort the process.
+    Unwind,
+    /// Return a [`BrokenPipeExit`] error to the caller of the write, which [`run`] turns into the
+    /// writer's usual termination once it propagates out of `main`.
+    ///
+    /// Unlike [`Action::Unwind`], this works with `panic = "abort"`, but relies on the program
+    /// to propagate the error rather than handle it.
+    Defer,
+}
+
+/// Details of a broken pipe that a [`Writer`] is about to act on.
+#[derive(Debug)]
+pub struct BrokenPipeEvent<'a> {
+    name: Option<&'a str>,
+    error: &'a io::Error,
+    stats: WriteStats,
+}
+
+impl<'a> BrokenPipeEvent<'a> {
+    /// Returns the name of the writer that detected the broken pipe, if it has one.
+    pub fn name(&self) -> Option<&'a str> {
+        self.name
+    }
+
+    /// Returns the error that the underlying writer reported.
+    pub fn error(&self) -> &'a io::Error {
+        self.error
+    }
+
+    /// Returns statistics about the writes that succeeded before the pipe broke.
+    pub fn stats(&self) -> WriteStats {
+        self.stats
+    }
+}
+
+/// Statistics about the writes that a [`Writer`] passed on successfully.
+///
+/// These don't include the write that failed, nor any writes discarded after
+/// [`Action::Discard`]. Both counts wrap around on overflow, which for bytes can happen after
+/// 4 GiB on targets with 32-bit pointers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriteStats {
+    writes: u64,
+    bytes: u64,
+}
+
+impl WriteStats {
+    /// Returns the number of successful calls into the underlying writer.
+    pub fn writes(&self) -> u64 {
+        self.writes
+    }
+
+    /// Returns the number of bytes that the underlying writer accepted.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+impl<W> fmt::Debug for Writer<W>
+where
+    W: Write,
+{
+    // Many writers worth wrapping don't implement Debug, so we only show our own state.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("Writer");
+        self.state.config.debug_fields(&mut d);
+        d.field("pipe_broken", &self.pipe_broken())
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl<W> fmt::Debug for Builder<W>
+where
+    W: Write,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("Builder");
+        self.config.debug_fields(&mut d);
+        d.finish()
+    }
+}
+
+impl Config {
+    fn debug_fields(&self, d: &mut fmt::DebugStruct<'_, '_>) {
+        d.field("name", &self.name)
+            .field("exit_code", &self.exit_code)
+            .field("signal", &self.signal)
+            .field("diagnostic", &self.diagnostic)
+            .field("also_terminate_on", &self.also_kinds)
+            .field("hook", &self.hook.is_some());
+    }
+}
+
+impl<W> Write for Writer<W>
+where
+    W: Write,
+{
+    // Rust 1.0.0 includes the following methods.
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.state
+            .write(&mut self.inner, buf.len(), |w| w.write(buf), |&n| n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.state.flush(&mut self.inner, |w| w.flush())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.state
+            .write(&mut self.inner, (), |w| w.write_all(buf), |_| buf.len())
+    }
+
+    fn write_fmt(&mut self, fmt: std::fmt::Arguments<'_>) -> io::Result<()> {
+        let counted = CountedArgs::new(fmt);
+        self.state.write(
+            &mut self.inner,
+            (),
+            |w| w.write_fmt(format_args!("{}", counted)),
+            |_| counted.len(),
+        )
+    }
+
+    // Rust 1.36.0 stabilizes write_vectored.
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        let len = bufs.iter().map(|b| b.len()).sum();
+        self.state
+            .write(&mut self.inner, len, |w| w.write_vectored(bufs), |&n| n)
+    }
+}
+
+impl<'a, W> Write for &'a Writer<W>
+where
+    W: Write,
+    &'a W: Write,
+{
+    // This mirrors the shared reference impls of std types like Stdout and File.
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.state
+            .write(&self.inner, buf.len(), |mut w| w.write(buf), |&n| n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.state.flush(&self.inner, |mut w| w.flush())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.state
+            .write(&self.inner, (), |mut w| w.write_all(buf), |_| buf.len())
+    }
+
+    fn write_fmt(&mut self, fmt: std::fmt::Arguments<'_>) -> io::Result<()> {
+        let counted = CountedArgs::new(fmt);
+        self.state.write(
+            &self.inner,
+            (),
+            |mut w| w.write_fmt(format_args!("{}", counted)),
+            |_| counted.len(),
+        )
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        let len = bufs.iter().map(|b| b.len()).sum();
+        self.state
+            .write(&self.inner, len, |mut w| w.write_vectored(bufs), |&n| n)
+    }
+}
+
+thread_local! {
+    static IN_HOOK: Cell<bool> = Cell::new(false);
+}
+
+// Runs a hook, unless another one is already running on this thread. Without this guard, a hook
+// flushing a sibling stream whose own hook does the same could recurse for as long as the streams
+// keep breaking.
+fn guard_hook<R>(hook: impl FnOnce() -> R) -> Option<R> {
+    if IN_HOOK.with(|in_hook| in_hook.replace(true)) {
+        return None;
+    }
+    struct Reset;
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            IN_HOOK.with(|in_hook| in_hook.set(false));
+        }
+    }
+    let _reset = Reset;
+    Some(hook())
+}
+
+type GlobalHook = dyn Fn(&BrokenPipeEvent<'_>) + Send + Sync;
+
+static GLOBAL_HOOK: AtomicPtr<Box<GlobalHook>> = AtomicPtr::new(ptr::null_mut());
+
+/// Registers a hook that every [`Writer`] calls right before it terminates the process, after
+/// any hook of its own, replacing any hook registered before.
+///
+/// This gives applications with several streams a single place for cleanup, much like
+/// [`std::panic::set_hook`]. It runs when a writer terminates directly, per [`Action::Terminate`]
+/// or [`Action::Exit`], and not for actions that leave termination to the program.
+///
+/// Replaced hooks are leaked, as another thread could still be running them.
+pub fn set_hook<F>(hook: F)
+where
+    F: Fn(&BrokenPipeEvent<'_>) + Send + Sync + 'static,
+{
+    let hook: Box<GlobalHook> = Box::new(hook);
+    GLOBAL_HOOK.store(Box::into_raw(Box::new(hook)), Ordering::Release);
+}
+
+fn diagnostic_from_env() -> bool {
+    match std::env::var_os("PIPECHECK_DIAGNOSTIC") {
+        Some(value) => value != "0",
+        None => false,
+    }
+}
+
+fn print_diagnostic(event: &BrokenPipeEvent<'_>) {
+    #[cfg(unix)]
+    {
+        // SAFETY: `isatty` accepts any file descriptor value.
+        if unsafe { libc::isatty(libc::STDERR_FILENO) } != 1 {
+            return;
+        }
+        let mut line = program_prefix();
+        line.push_str(&format!(
+            "broken pipe on {} after {} bytes\n",
+            event.name().unwrap_or("output"),
+            event.stats().bytes(),
+        ));
+        // Like the rest of the termination sequence, this is best effort.
+        let _ = io::stderr().write_all(line.as_bytes());
+    }
+    #[cfg(not(unix))]
+    let _ = event;
+}
+
+// Returns a prefix like "grep: " for notes on stderr, or nothing if the program has no name.
+#[cfg(unix)]
+fn program_prefix() -> String {
+    let program = std::env::args_os()
+        .next()
+        .map(std::path::PathBuf::from)
+        .and_then(|path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        });
+    match program {
+        Some(program) => format!("{}: ", program),
+        None => String::new(),
+    }
+}
+
+// The statistics that PIPECHECK_STATS reports at exit, summed over all writers with a name.
+#[derive(Default)]
+struct Totals {
+    writes: AtomicUsize,
+    bytes: AtomicUsize,
+    broken: AtomicBool,
+}
+
+#[cfg(unix)]
+fn register_totals(config: &Config) -> Option<Arc<Totals>> {
+    let name = config.name.as_ref()?;
+    match std::env::var_os("PIPECHECK_STATS") {
+        Some(ref value) if value != "0" => {}
+        _ => return None,
+    }
+    install_exit_handler().ok()?;
+    let mut registry = totals_registry()
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    let totals = registry.entry(name.to_string()).or_default();
+    Some(Arc::clone(totals))
+}
+
+#[cfg(not(unix))]
+fn register_totals(_config: &Config) -> Option<Arc<Totals>> {
+    None
+}
+
+#[cfg(unix)]
+fn totals_registry() -> &'static Mutex<BTreeMap<String, Arc<Totals>>> {
+    // As with the flush registry, Mutex::new isn't const on our minimum Rust version.
+    static INIT: Once = Once::new();
+    static REGISTRY: AtomicPtr<Mutex<BTreeMap<String, Arc<Totals>>>> =
+        AtomicPtr::new(ptr::null_mut());
+    INIT.call_once(|| {
+        let registry = Box::new(Mutex::new(BTreeMap::new()));
+        REGISTRY.store(Box::into_raw(registry), Ordering::Release);
+    });
+    // SAFETY: The pointer comes from Box::into_raw above once INIT completes, and is never freed.
+    unsafe { &*REGISTRY.load(Ordering::Acquire) }
+}
+
+#[cfg(unix)]
+fn report_totals() {
+    let registry = match totals_registry().try_lock() {
+        Ok(registry) => registry,
+        Err(TryLockError::Poisoned(err)) => err.into_inner(),
+        Err(TryLockError::WouldBlock) => return,
+    };
+    if registry.is_empty() {
+        return;
+    }
+    let mut line = program_prefix();
+    line.push_str("pipecheck stats:");
+    for (i, (name, totals)) in registry.iter().enumerate() {
+        line.push_str(&format!(
+            "{} {} {} bytes in {} writes",
+            if i == 0 { "" } else { "," },
+            name,
+            totals.bytes.load(Ordering::Relaxed),
+            totals.writes.load(Ordering::Relaxed),
+        ));
+        if totals.broken.load(Ordering::Relaxed) {
+            line.push_str(" (broken)");
+        }
+    }
+    line.push('\n');
+    let _ = io::stderr().write_all(line.as_bytes());
+}
+
+type Flush = dyn Fn() + Send + Sync;
+
+/// Registers a shared writer to flush right before any termination for a broken pipe, such as a
+/// buffered log on stderr that would otherwise lose its last lines when stdout breaks, as well as
+/// in [`exit`].
+///
+/// Flushes happen after all hooks, in the order of registration, and ignore errors. A writer
+/// whose lock is held at the time (e.g. by the thread that is terminating) is skipped rather than
+/// risking a deadlock.
+pub fn register_flush<W>(handle: Arc<Mutex<W>>)
+where
+    W: Write + Send + 'static,
+{
+    let flush: Box<Flush> = Box::new(move || {
+        let mut w = match handle.try_lock() {
+            Ok(w) => w,
+            Err(TryLockError::Poisoned(err)) => err.into_inner(),
+            Err(TryLockError::WouldBlock) => return,
+        };
+        let _ = w.flush();
+    });
+    flush_registry()
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .push(flush);
+}
+
+fn flush_registry() -> &'static Mutex<Vec<Box<Flush>>> {
+    // Mutex::new isn't const on our minimum Rust version, so we allocate the registry once.
+    static INIT: Once = Once::new();
+    static REGISTRY: AtomicPtr<Mutex<Vec<Box<Flush>>>> = AtomicPtr::new(ptr::null_mut());
+    INIT.call_once(|| {
+        let registry = Box::new(Mutex::new(Vec::new()));
+        REGISTRY.store(Box::into_raw(registry), Ordering::Release);
+    });
+    // SAFETY: The pointer comes from Box::into_raw above once INIT completes, and is never freed.
+    unsafe { &*REGISTRY.load(Ordering::Acquire) }
+}
+
+fn flush_registered() {
+    // Flushing under the hook guard makes a broken pipe in a registered writer terminate
+    // right away, rather than starting another round of flushes.
+    guard_hook(|| {
+        let registry = match flush_registry().try_lock() {
+            Ok(registry) => registry,
+            Err(TryLockError::Poisoned(err)) => err.into_inner(),
+            Err(TryLockError::WouldBlock) => return,
+        };
+        for flush in registry.iter() {
+            flush();
+        }
+    });
+}
+
+fn run_global_hook(event: &BrokenPipeEvent<'_>) {
+    let hook = GLOBAL_HOOK.load(Ordering::Acquire);
+    if !hook.is_null() {
+        // SAFETY: Non-null values come from Box::into_raw in set_hook and are never freed.
+        let hook = unsafe { &**hook };
+        guard_hook(|| hook(event));
+    }
+}
+
+// Counts the bytes of formatted output that pass through it, while leaving the underlying
+// writer's own write_fmt in charge (which matters for Stdout, as it locks once per call).
+struct CountedArgs<'a> {
+    args: fmt::Arguments<'a>,
+    len: Cell<usize>,
+}
+
+impl<'a> CountedArgs<'a> {
+    fn new(args: fmt::Arguments<'a>) -> CountedArgs<'a> {
+        CountedArgs {
+            args,
+            len: Cell::new(0),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len.get()
+    }
+}
+
+impl<'a> fmt::Display for CountedArgs<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        struct Counter<'a, 'b> {
+            f: &'a mut fmt::Formatter<'b>,
+            len: &'a Cell<usize>,
+        }
+        impl<'a, 'b> fmt::Write for Counter<'a, 'b> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                self.f.write_str(s)?;
+                self.len.set(self.len.get() + s.len());
+                Ok(())
+            }
+        }
+        fmt::write(&mut Counter { f, len: &self.len }, self.args)
+    }
+}
+
+impl State {
+    // `discarded` is what a write reports to its caller when its output is discarded, and
+    // `written` is how many bytes a successful write passed on.
+    fn write<I, T>(
+        &self,
+        inner: I,
+        discarded: T,
+        write: impl FnOnce(I) -> io::Result<T>,
+        written: impl FnOnce(&T) -> usize,
+    ) -> io::Result<T> {
+        if self.discarding.load(Ordering::Relaxed) {
+            return Ok(discarded);
+        }
+        #[cfg(any(test, feature = "testing"))]
+        let result = testing::injected_fault().and_then(|()| write(inner));
+        #[cfg(not(any(test, feature = "testing")))]
+        let result = write(inner);
+        if let Ok(ref value) = result {
+            let len = written(value);
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            self.bytes.fetch_add(len, Ordering::Relaxed);
+            if let Some(ref totals) = self.totals {
+                totals.writes.fetch_add(1, Ordering::Relaxed);
+                totals.bytes.fetch_add(len, Ordering::Relaxed);
+            }
+        }
+        self.check(result, discarded)
+    }
+
+    fn stats(&self) -> WriteStats {
+        WriteStats {
+            writes: self.writes.load(Ordering::Relaxed) as u64,
+            bytes: self.bytes.load(Ordering::Relaxed) as u64,
+        }
+    }
+
+    fn flush<I>(&self, inner: I, flush: impl FnOnce(I) -> io::Result<()>) -> io::Result<()> {
+        if self.discarding.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.check(flush(inner), ())
+    }
+
+    fn check<T>(&self, result: io::Result<T>, discarded: T) -> io::Result<T> {
+        let config = &self.config;
+        let action = match result {
+            Err(ref err) if is_broken_pipe_or(err, &config.also_kinds) => {
+                if let Some(ref totals) = self.totals {
+                    totals.broken.store(true, Ordering::Relaxed);
+                }
+                if EXITING.load(Ordering::Acquire) {
+                    return result;
+                }
+                let event = BrokenPipeEvent {
+                    name: config.name.as_ref().map(|name| &**name),
+                    error: err,
+                    stats: self.stats(),
+                };
+                let action = match config.hook {
+                    Some(ref hook) => guard_hook(|| (hook.0)(&event)).unwrap_or(Action::Terminate),
+                    None => Action::Terminate,
+                };
+                // A second panic while one is already unwinding would abort the process.
+                let action = match action {
+                    Action::Unwind if thread::panicking() => Action::Terminate,
+                    action => action,
+                };
+                if let Action::Terminate | Action::Exit(_) = action {
+                    run_global_hook(&event);
+                    if config.diagnostic.unwrap_or_else(diagnostic_from_env) {
+                        print_diagnostic(&event);
+                    }
+                }
+                action
+            }
+            _ => return result,
+        };
+        match action {
+            Action::Terminate => terminate(config.signal, config.exit_code),
+            Action::Exit(code) => exit(code),
+            Action::Error => match config.name {
+                Some(ref name) => result.map_err(|err| {
+                    io::Error::new(
+                        err.kind(),
+                        WriteContext {
+                            name: name.to_string(),
+                            error: err,
+                        },
+                    )
+                }),
+                None => result,
+            },
+            Action::Discard => {
+                self.discarding.store(true, Ordering::Relaxed);
+                Ok(discarded)
+            }
+            Action::Unwind => panic::resume_unwind(Box::new(BrokenPipePanic {
+                signal: config.signal,
+                exit_code: config.exit_code,
+            })),
+            Action::Defer => result.map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    BrokenPipeExit {
+                        error: err,
+                        signal: config.signal,
+                        exit_code: config.exit_code,
+                    },
+                )
+            }),
+        }
+    }
+}
+
+/// A buffered [`Writer`], whose final flush on drop terminates on a broken pipe like any other
+/// write.
+///
+/// This is an [`io::BufWriter`] around a `Writer`, which is the order that checks every write.
+/// The other order leaves the flush in `io::BufWriter`'s `Drop` unchecked, and std ignores any
+/// error there.
+///
+/// A flush with nothing written since the last one returns right away, without flushing the
+/// underlying writer again, for frameworks that flush after every record.
+///
+/// To buffer a customized `Writer`, convert it with [`From`], or wrap it in
+/// [`io::BufWriter::with_capacity`] for a custom capacity too.
+pub struct BufWriter<W>
+where
+    W: Write,
+{
+    inner: io::BufWriter<Writer<W>>,
+    dirty: bool,
+}
+
+impl<W> BufWriter<W>
+where
+    W: Write,
+{
+    /// Buffers `w` with a default capacity.
+    pub fn new(w: W) -> BufWriter<W> {
+        BufWriter::from(Writer::new(w))
+    }
+
+    /// Buffers `w` with at least the given capacity in bytes.
+    pub fn with_capacity(capacity: usize, w: W) -> BufWriter<W> {
+        BufWriter {
+            inner: io::BufWriter::with_capacity(capacity, Writer::new(w)),
+            dirty: false,
+        }
+    }
+
+    /// Returns a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        self.inner.get_ref().get_ref()
+    }
+
+    /// Returns a mutable reference to the underlying writer.
+    ///
+    /// Writing directly to the underlying writer bypasses both the buffer and pipecheck.
+    pub fn get_mut(&mut self) -> &mut W {
+        // We can't tell what the caller writes, so the next flush has to go through.
+        self.dirty = true;
+        self.inner.get_mut().get_mut()
+    }
+
+    /// Flushes the buffer and returns the underlying writer.
+    pub fn into_inner(self) -> io::Result<W> {
+        match self.inner.into_inner() {
+            Ok(w) => Ok(w.into_inner()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl<W> From<Writer<W>> for BufWriter<W>
+where
+    W: Write,
+{
+    fn from(w: Writer<W>) -> BufWriter<W> {
+        BufWriter {
+            inner: io::BufWriter::new(w),
+            dirty: false,
+        }
+    }
+}
+
+impl<W> fmt::Debug for BufWriter<W>
+where
+    W: Write,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufWriter")
+            .field("writer", self.inner.get_ref())
+            .finish()
+    }
+}
+
+impl<W> Write for BufWriter<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.dirty = true;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.dirty {
+            self.inner.flush()?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.dirty = true;
+        self.inner.write_all(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        self.dirty = true;
+        self.inner.write_vectored(bufs)
+    }
+}
+
+/// A line buffered [`Writer`], which flushes on every newline and terminates on a broken pipe
+/// during those implicit flushes too.
+///
+/// This is an [`io::LineWriter`] around a `Writer`, in the same order as [`BufWriter`] for the
+/// same reasons. It suits interactive consumers like `fzf` that act on each line as it arrives.
+/// Like [`BufWriter`], it skips flushes with nothing written since the last one.
+pub struct LineWriter<W>
+where
+    W: Write,
+{
+    inner: io::LineWriter<Writer<W>>,
+    dirty: bool,
+}
+
+impl<W> LineWriter<W>
+where
+    W: Write,
+{
+    /// Line buffers `w` with a default capacity.
+    pub fn new(w: W) -> LineWriter<W> {
+        LineWriter::from(Writer::new(w))
+    }
+
+    /// Line buffers `w` with at least the given capacity in bytes.
+    pub fn with_capacity(capacity: usize, w: W) -> LineWriter<W> {
+        LineWriter {
+            inner: io::LineWriter::with_capacity(capacity, Writer::new(w)),
+            dirty: false,
+        }
+    }
+
+    /// Returns a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        self.inner.get_ref().get_ref()
+    }
+
+    /// Returns a mutable reference to the underlying writer.
+    ///
+    /// Writing directly to the underlying writer bypasses both the buffer and pipecheck.
+    pub fn get_mut(&mut self) -> &mut W {
+        // We can't tell what the caller writes, so the next flush has to go through.
+        self.dirty = true;
+        self.inner.get_mut().get_mut()
+    }
+
+    /// Flushes the buffer and returns the underlying writer.
+    pub fn into_inner(self) -> io::Result<W> {
+        match self.inner.into_inner() {
+            Ok(w) => Ok(w.into_inner()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl<W> From<Writer<W>> for LineWriter<W>
+where
+    W: Write,
+{
+    fn from(w: Writer<W>) -> LineWriter<W> {
+        LineWriter {
+            inner: io::LineWriter::new(w),
+            dirty: false,
+        }
+    }
+}
+
+impl<W> fmt::Debug for LineWriter<W>
+where
+    W: Write,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LineWriter")
+            .field("writer", self.inner.get_ref())
+            .finish()
+    }
+}
+
+impl<W> Write for LineWriter<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.dirty = true;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.dirty {
+            self.inner.flush()?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.dirty = true;
+        self.inner.write_all(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        self.dirty = true;
+        self.inner.write_vectored(bufs)
+    }
+}
+
+/// A [`Writer`] shared between threads, which writes only whole lines so that the output of
+/// concurrent producers never interleaves within a line.
+///
+/// Each clone collects its own partial line, and writes every complete line it has under a lock
+/// shared by all clones. A flush (including the one on drop) writes out a trailing partial line
+/// too. A broken pipe from any clone terminates the process as the shared `Writer` is configured
+/// to.
+pub struct SyncLineWriter<W>
+where
+    W: Write,
+{
+    shared: Arc<Mutex<Writer<W>>>,
+    pending: Vec<u8>,
+}
+
+impl<W> SyncLineWriter<W>
+where
+    W: Write,
+{
+    /// Shares `w` for writing whole lines.
+    pub fn new(w: W) -> SyncLineWriter<W> {
+        SyncLineWriter::from(Writer::new(w))
+    }
+
+    fn write_pending(&mut self, len: usize) -> io::Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        // A panic elsewhere can't leave a Writer in an inconsistent state, so we ignore poisoning.
+        let result = self
+            .shared
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .write_all(&self.pending[..len]);
+        self.pending.drain(..len);
+        result
+    }
+}
+
+impl<W> From<Writer<W>> for SyncLineWriter<W>
+where
+    W: Write,
+{
+    fn from(w: Writer<W>) -> SyncLineWriter<W> {
+        SyncLineWriter {
+            shared: Arc::new(Mutex::new(w)),
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl<W> Clone for SyncLineWriter<W>
+where
+    W: Write,
+{
+    fn clone(&self) -> SyncLineWriter<W> {
+        SyncLineWriter {
+            shared: Arc::clone(&self.shared),
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl<W> fmt::Debug for SyncLineWriter<W>
+where
+    W: Write,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SyncLineWriter")
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+impl<W> Write for SyncLineWriter<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // The partial line after the last newline only becomes pending once the complete lines
+        // before it are written, so a failed write doesn't leave part of `buf` behind.
+        let tail = match buf.iter().rposition(|&b| b == b'\n') {
+            Some(i) => {
+                self.pending.extend_from_slice(&buf[..=i]);
+                let len = self.pending.len();
+                self.write_pending(len)?;
+                &buf[i + 1..]
+            }
+            None => buf,
+        };
+        self.pending.extend_from_slice(tail);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let len = self.pending.len();
+        self.write_pending(len)?;
+        self.shared
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .flush()
+    }
+}
+
+impl<W> Drop for SyncLineWriter<W>
+where
+    W: Write,
+{
+    fn drop(&mut self) {
+        // Like std's buffered writers, we have nowhere to report an error from here.
+        let _ = self.flush();
+    }
+}
+
+/// Moves `w` to a dedicated output thread, and returns a cloneable handle for writing to it.
+///
+/// Each write through a [`WriterHandle`] hands a copy of its data to the thread, which performs
+/// the actual writes in order, so producers only block once 64 writes are waiting on a slow
+/// downstream. Wrap handles in an [`io::BufWriter`] to send larger chunks. A broken pipe
+/// terminates the process from the output thread as `w` is configured to.
+///
+/// The output thread exits once every handle is dropped, but the process doesn't wait for it.
+/// Flush a handle before returning from `main`, which waits for everything written before it
+/// to reach `w`.
+pub fn spawn_writer<W>(w: Writer<W>) -> io::Result<WriterHandle>
+where
+    W: Write + Send + 'static,
+{
+    let (sender, receiver) = mpsc::sync_channel(64);
+    thread::Builder::new()
+        .name("pipecheck writer".to_owned())
+        .spawn(move || drain(w, receiver))?;
+    Ok(WriterHandle { sender })
+}
+
+fn drain<W: Write>(mut w: Writer<W>, receiver: mpsc::Receiver<Message>) {
+    // After an error we keep draining, so handles don't block, and report it at the next flush.
+    let mut failed: Option<io::Error> = None;
+    for message in receiver {
+        match message {
+            Message::Write(data) => {
+                if failed.is_none() {
+                    failed = w.write_all(&data).err();
+                }
+            }
+            Message::Flush(ack) => {
+                let result = match failed {
+                    Some(ref err) => Err(io::Error::new(err.kind(), err.to_string())),
+                    None => w.flush(),
+                };
+                let _ = ack.send(result);
+            }
+        }
+    }
+    if failed.is_none() {
+        let _ = w.flush();
+    }
+}
+
+enum Message {
+    Write(Vec<u8>),
+    Flush(mpsc::SyncSender<io::Result<()>>),
+}
+
+/// A handle for writing to the output thread of a [`spawn_writer`] call.
+///
+/// A write copies its data to the output thread and reports success right away. A flush waits
+/// for the output thread to flush the writer, and reports the first error any earlier write from
+/// any handle ran into.
+#[derive(Clone)]
+pub struct WriterHandle {
+    sender: mpsc::SyncSender<Message>,
+}
+
+impl WriterHandle {
+    fn send(&self, message: Message) -> io::Result<()> {
+        // The output thread only goes away early if the writer panics.
+        self.sender
+            .send(message)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "pipecheck output thread exited"))
+    }
+}
+
+impl fmt::Debug for WriterHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriterHandle").finish()
+    }
+}
+
+impl Write for WriterHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send(Message::Write(buf.to_vec()))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let (ack, result) = mpsc::sync_channel(1);
+        self.send(Message::Flush(ack))?;
+        result.recv().unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                "pipecheck output thread exited",
+            ))
+        })
+    }
+}
+
+/// A [`Writer`] shared between parallel workers, which writes their chunks of output in sequence
+/// order regardless of the order they finish in, like `parallel --keep-order`.
+///
+/// Workers [`submit`](OrderedSink::submit) each chunk along with its sequence number, counting
+/// from 0 with every number used exactly once. Whichever submission fills the next gap writes out
+/// every chunk that is then in order, and later chunks wait in memory. A broken pipe terminates
+/// the whole process as the `Writer` is configured to; with [`Action::Discard`], workers can poll
+/// [`OrderedSink::pipe_broken`] to stop early instead.
+pub struct OrderedSink<W>
+where
+    W: Write,
+{
+    shared: Arc<Mutex<Ordered<W>>>,
+}
+
+struct Ordered<W>
+where
+    W: Write,
+{
+    writer: Writer<W>,
+    next: u64,
+    waiting: BTreeMap<u64, Vec<u8>>,
+}
+
+impl<W> OrderedSink<W>
+where
+    W: Write,
+{
+    /// Shares `w` for writing chunks in sequence order.
+    pub fn new(w: Writer<W>) -> OrderedSink<W> {
+        OrderedSink {
+            shared: Arc::new(Mutex::new(Ordered {
+                writer: w,
+                next: 0,
+                waiting: BTreeMap::new(),
+            })),
+        }
+    }
+
+    /// Submits the chunk with sequence number `seq`, and writes out every chunk that is now in
+    /// order.
+    ///
+    /// An error comes from writing this or any earlier waiting chunk, and the chunks are consumed
+    /// either way. A sequence number that was already submitted fails with
+    /// [`InvalidInput`](io::ErrorKind::InvalidInput) instead, as its chunk could never be written.
+    pub fn submit(&self, seq: u64, chunk: Vec<u8>) -> io::Result<()> {
+        let mut ordered = self.lock();
+        let ordered = &mut *ordered;
+        if seq < ordered.next || ordered.waiting.contains_key(&seq) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("sequence number {} was already submitted", seq),
+            ));
+        }
+        if seq != ordered.next {
+            ordered.waiting.insert(seq, chunk);
+            return Ok(());
+        }
+        let mut chunk = chunk;
+        loop {
+            ordered.next += 1;
+            ordered.writer.write_all(&chunk)?;
+            chunk = match ordered.waiting.remove(&ordered.next) {
+                Some(chunk) => chunk,
+                None => return Ok(()),
+            };
+        }
+    }
+
+    /// Flushes the underlying writer. Chunks still waiting for an earlier one aren't written.
+    pub fn flush(&self) -> io::Result<()> {
+        self.lock().writer.flush()
+    }
+
+    /// Returns whether the writer has stopped writing because of [`Action::Discard`].
+    pub fn pipe_broken(&self) -> bool {
+        self.lock().writer.pipe_broken()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Ordered<W>> {
+        // As for SyncLineWriter, a panic elsewhere can't leave anything inconsistent.
+        self.shared.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl<W> Clone for OrderedSink<W>
+where
+    W: Write,
+{
+    fn clone(&self) -> OrderedSink<W> {
+        OrderedSink {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<W> fmt::Debug for OrderedSink<W>
+where
+    W: Write,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ordered = self.lock();
+        f.debug_struct("OrderedSink")
+            .field("writer", &ordered.writer)
+            .field("next", &ordered.next)
+            .field("waiting", &ordered.waiting.len())
+            .finish()
+    }
+}
+
+/// A writer that duplicates all output to several branches, each with its own policy for
+/// errors.
+///
+/// A [`branch`](Tee::branch) is a [`Writer`], which handles broken pipes as it is configured to
+/// and hands any other error to the caller. An [`optional_branch`](Tee::optional_branch), such as
+/// a log file, is silently dropped on its first error of any kind, and the other branches carry
+/// on. Writes go to each branch in the order they were added, and an error from a branch only
+/// comes back once every other branch has had the write too. With several failing branches, the
+/// error is the one from the branch added first.
+#[derive(Default)]
+pub struct Tee<'a> {
+    branches: Vec<Branch<'a>>,
+}
+
+struct Branch<'a> {
+    writer: Box<dyn Write + 'a>,
+    optional: bool,
+    dropped: bool,
+}
+
+impl<'a> Tee<'a> {
+    /// Creates a `Tee` without any branches.
+    pub fn new() -> Tee<'a> {
+        Tee::default()
+    }
+
+    /// Adds a branch that handles broken pipes as `w` is configured to.
+    pub fn branch<W>(mut self, w: Writer<W>) -> Tee<'a>
+    where
+        W: Write + 'a,
+    {
+        self.branches.push(Branch {
+            writer: Box::new(w),
+            optional: false,
+            dropped: false,
+        });
+        self
+    }
+
+    /// Adds a branch that is silently dropped on its first error.
+    pub fn optional_branch<W>(mut self, w: W) -> Tee<'a>
+    where
+        W: Write + 'a,
+    {
+        self.branches.push(Branch {
+            writer: Box::new(w),
+            optional: true,
+            dropped: false,
+        });
+        self
+    }
+
+    fn for_each_branch(
+        &mut self,
+        mut f: impl FnMut(&mut dyn Write) -> io::Result<()>,
+    ) -> io::Result<()> {
+        // Every branch gets the buffer before we report an error, as a caller that retries would
+        // otherwise duplicate it in the branches that already took it.
+        let mut result = Ok(());
+        for branch in self.branches.iter_mut().filter(|b| !b.dropped) {
+            match f(&mut *branch.writer) {
+                Err(_) if branch.optional => branch.dropped = true,
+                Err(err) if result.is_ok() => result = Err(err),
+                _ => {}
+            }
+        }
+        result
+    }
+}
+
+impl<'a> fmt::Debug for Tee<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let live = self.branches.iter().filter(|b| !b.dropped).count();
+        f.debug_struct("Tee")
+            .field("branches", &self.branches.len())
+            .field("dropped", &(self.branches.len() - live))
+            .finish()
+    }
+}
+
+impl<'a> Write for Tee<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Branches could accept different amounts, so each one gets the whole buffer.
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.for_each_branch(|w| w.flush())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.for_each_branch(|w| w.write_all(buf))
+    }
+}
+
+/// A writer that acts as if its downstream closed once it reaches a byte or line limit.
+///
+/// After passing through the configured amount of output, a `LimitWriter` flushes its underlying
+/// writer and fails any further write with a [`BrokenPipe`](io::ErrorKind::BrokenPipe) error.
+/// Wrapping it in a [`Writer`] lets `--max-count` or `head`-style options reuse the same early
+/// exit as a real broken pipe.
+pub struct LimitWriter<W>
+where
+    W: Write,
+{
+    inner: W,
+    unit: LimitUnit,
+    remaining: u64,
+}
+
+#[derive(Debug)]
+enum LimitUnit {
+    Bytes,
+    Lines,
+}
+
+impl<W> LimitWriter<W>
+where
+    W: Write,
+{
+    /// Limits output to the first `limit` bytes.
+    pub fn bytes(w: W, limit: u64) -> LimitWriter<W> {
+        LimitWriter {
+            inner: w,
+            unit: LimitUnit::Bytes,
+            remaining: limit,
+        }
+    }
+
+    /// Limits output to the first `limit` newline-terminated lines.
+    pub fn lines(w: W, limit: u64) -> LimitWriter<W> {
+        LimitWriter {
+            inner: w,
+            unit: LimitUnit::Lines,
+            remaining: limit,
+        }
+    }
+
+    /// Returns how many more bytes or lines this writer passes through before it acts as if its
+    /// downstream closed.
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// Gets a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Gets a mutable reference to the underlying writer.
+    ///
+    /// Writes made directly through this reference don't count toward the limit.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Unwraps this `LimitWriter`, returning the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W> fmt::Debug for LimitWriter<W>
+where
+    W: Write,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LimitWriter")
+            .field("unit", &self.unit)
+            .field("remaining", &self.remaining)
+            .finish()
+    }
+}
+
+impl<W> Write for LimitWriter<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            // Whatever made it under the limit should reach the downstream before
+            // the caller reacts to the "broken pipe" by exiting.
+            self.inner.flush()?;
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "output limit reached",
+            ));
+        }
+
+        let len = match self.unit {
+            LimitUnit::Bytes if (buf.len() as u64) > self.remaining => self.remaining as usize,
+            LimitUnit::Bytes => buf.len(),
+            LimitUnit::Lines => {
+                let mut lines = 0;
+                let nth_newline = buf.iter().position(|&b| {
+                    lines += (b == b'\n') as u64;
+                    lines == self.remaining
+                });
+                nth_newline.map_or(buf.len(), |i| i + 1)
+            }
+        };
+        let n = self.inner.write(&buf[..len])?;
+        self.remaining -= match self.unit {
+            LimitUnit::Bytes => n as u64,
+            LimitUnit::Lines => buf[..n].iter().filter(|&&b| b == b'\n').count() as u64,
+        };
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// An iterator adapter that writes each item to a [`Writer`] as a line, and stops as soon as the
+/// downstream goes away.
+///
+/// Each call to `next` writes the next item followed by a newline, then yields the item. A broken
+/// pipe that the writer hands back per [`Action::Error`] or discards per [`Action::Discard`] ends
+/// the iteration without pulling any more items, so generator-style code stops producing output
+/// that no one reads. Any other write error is yielded once, and also ends the iteration. With
+/// the default [`Action::Terminate`], the process terminates at the broken pipe as usual.
+pub struct CheckedLines<'a, W, I>
+where
+    W: Write,
+{
+    writer: &'a mut Writer<W>,
+    items: I,
+    done: bool,
+}
+
+impl<'a, W, I> CheckedLines<'a, W, I>
+where
+    W: Write,
+    I: Iterator,
+{
+    /// Writes the items of `items` to `writer` as they're iterated over.
+    pub fn new<T>(writer: &'a mut Writer<W>, items: T) -> CheckedLines<'a, W, I>
+    where
+        T: IntoIterator<IntoIter = I>,
+    {
+        CheckedLines {
+            writer,
+            items: items.into_iter(),
+            done: false,
+        }
+    }
+}
+
+impl<'a, W, I> fmt::Debug for CheckedLines<'a, W, I>
+where
+    W: Write,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CheckedLines")
+            .field("writer", &self.writer)
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+impl<'a, W, I> Iterator for CheckedLines<'a, W, I>
+where
+    W: Write,
+    I: Iterator,
+    I::Item: AsRef<[u8]>,
+{
+    type Item = io::Result<I::Item>;
+
+    fn next(&mut self) -> Option<io::Result<I::Item>> {
+        if self.done || self.writer.pipe_broken() {
+            return None;
+        }
+        let item = self.items.next()?;
+        let writer = &mut *self.writer;
+        let result = writer
+            .write_all(item.as_ref())
+            .and_then(|()| writer.write_all(b"\n"));
+        match result {
+            Ok(()) if !writer.pipe_broken() => return Some(Ok(item)),
+            Ok(()) => {}
+            Err(ref err) if is_broken_pipe_or(err, &writer.state.config.also_kinds) => {}
+            Err(err) => {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+        self.done = true;
+        None
+    }
+}
+
+/// A writer for a borrowed Unix file descriptor, which writes with `write(2)` and `writev(2)`
+/// directly.
+///
+/// This skips std's buffering entirely, including the line buffering of [`io::Stdout`], for
+/// programs that buffer their own output. Interrupted writes are retried, and with
+/// [`wait_when_blocked`](RawFdWriter::wait_when_blocked), writes to a non-blocking descriptor
+/// wait for it to become writable instead of failing. Like [`LimitWriter`], it only reports
+/// errors, so wrap it in a [`Writer`] to terminate on a broken pipe, as in
+/// `RawFdWriter::stdout().pipecheck()`.
+///
+/// Anything still sitting in std's own stdout buffer may come out after output written here.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct RawFdWriter {
+    fd: RawFd,
+    wait_when_blocked: bool,
+    write_timeout: Option<Duration>,
+}
+
+#[cfg(unix)]
+impl RawFdWriter {
+    /// Writes to the standard output descriptor.
+    pub fn stdout() -> RawFdWriter {
+        // SAFETY: Like io::Stdout, we simply write to whatever the descriptor refers to.
+        unsafe { RawFdWriter::borrow_raw(libc::STDOUT_FILENO) }
+    }
+
+    /// Writes to the standard error descriptor.
+    pub fn stderr() -> RawFdWriter {
+        // SAFETY: As for stdout.
+        unsafe { RawFdWriter::borrow_raw(libc::STDERR_FILENO) }
+    }
+
+    /// Writes to `fd` without taking ownership of it.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must remain open for the lifetime of the returned writer, as for
+    /// `BorrowedFd::borrow_raw`.
+    pub unsafe fn borrow_raw(fd: RawFd) -> RawFdWriter {
+        RawFdWriter {
+            fd,
+            wait_when_blocked: false,
+            write_timeout: None,
+        }
+    }
+
+    /// Sets whether a write that would block waits for the descriptor to become writable with
+    /// `poll(2)`, rather than failing with [`WouldBlock`](io::ErrorKind::WouldBlock). Off by
+    /// default.
+    pub fn wait_when_blocked(mut self, wait: bool) -> RawFdWriter {
+        self.wait_when_blocked = wait;
+        self
+    }
+
+    /// Sets how long a write may wait for the descriptor to become writable before failing with
+    /// [`TimedOut`](io::ErrorKind::TimedOut), for downstreams that wedge rather than close. None
+    /// by default.
+    ///
+    /// With a timeout, each write waits with `poll(2)` first and then writes at most `PIPE_BUF`
+    /// bytes, which a writable pipe accepts without blocking. To terminate on a stall like on a
+    /// broken pipe, wrap this in a `Writer` built with
+    /// [`also_terminate_on(&[io::ErrorKind::TimedOut])`](Builder::also_terminate_on).
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> RawFdWriter {
+        self.write_timeout = timeout;
+        self
+    }
+
+    fn retry(&self, mut write: impl FnMut() -> io::Result<usize>) -> io::Result<usize> {
+        loop {
+            match write() {
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref err)
+                    if err.kind() == io::ErrorKind::WouldBlock && self.wait_when_blocked =>
+                {
+                    unix::wait_writable(self.fd, self.write_timeout)?
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for RawFdWriter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+#[cfg(unix)]
+impl Write for RawFdWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let fd = self.fd;
+        let buf = match self.write_timeout {
+            Some(timeout) => {
+                unix::wait_writable(fd, Some(timeout))?;
+                &buf[..cmp::min(buf.len(), unix::PIPE_BUF)]
+            }
+            None => buf,
+        };
+        self.retry(|| unix::write(fd, buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        if self.write_timeout.is_some() {
+            // Short of splitting buffers up, a single one is the simplest way to stay in bounds.
+            let buf = bufs
+                .iter()
+                .find(|b| !b.is_empty())
+                .map_or(&[][..], |b| &**b);
+            return self.write(buf);
+        }
+        let fd = self.fd;
+        self.retry(|| unix::writev(fd, bufs))
+    }
+}
+
+/// Copies everything from `reader` to `writer`, using the kernel's copy offloads where possible,
+/// and returns the number of bytes copied.
+///
+/// On Linux, this moves data with `splice(2)` when either side is a pipe, `copy_file_range(2)`
+/// between regular files, and `sendfile(2)` from a regular file, falling back to [`io::copy`]
+/// when none apply or `writer` has a [write timeout](RawFdWriter::write_timeout), which the
+/// offloads can't honor. Broken pipes along the way get the same treatment as from any other write
+/// through `writer`, which [`io::copy`] can't offer without bypassing its own specializations.
+///
+/// The offloads read from `reader`'s descriptor directly, so `reader` must not hold buffered data
+/// of its own (such as input that std's `Stdin` read ahead).
+#[cfg(unix)]
+pub fn copy<R>(reader: &mut R, writer: &mut Writer<RawFdWriter>) -> io::Result<u64>
+where
+    R: Read + AsRawFd + ?Sized,
+{
+    #[cfg(target_os = "linux")]
+    let copied = {
+        let (copied, done) = offload_copy(reader.as_raw_fd(), writer)?;
+        if done {
+            return Ok(copied);
+        }
+        copied
+    };
+    #[cfg(not(target_os = "linux"))]
+    let copied = 0;
+    Ok(copied + io::copy(reader, writer)?)
+}
+
+#[cfg(target_os = "linux")]
+fn offload_copy(in_fd: RawFd, writer: &mut Writer<RawFdWriter>) -> io::Result<(u64, bool)> {
+    // The offloads block in the kernel with no way to honor a write timeout. A descriptor that
+    // would block without one makes them fail with EAGAIN, which falls back to io::copy instead.
+    if writer.inner.write_timeout.is_some() {
+        return Ok((0, false));
+    }
+    let mut copied = 0;
+    let mut offload = unix::Offload::choose(in_fd, writer.inner.fd);
+    while let Some(method) = offload {
+        let result = writer
+            .state
+            .write(&mut writer.inner, 0, |w| method.copy(in_fd, w.fd), |&n| n);
+        match result {
+            // Like std, we can't tell EOF from an offload that doesn't apply to these descriptors
+            // until something was copied, so a first call copying nothing falls back to io::copy.
+            // A discarding writer also reports that nothing was copied, which ends the copy.
+            Ok(0) if copied == 0 && !writer.pipe_broken() => return Ok((0, false)),
+            Ok(0) => return Ok((copied, true)),
+            Ok(n) => copied += n as u64,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(ref err) if unix::is_unsupported_offload(err) => offload = method.fallback(),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok((copied, false))
+}
+
+/// Watches the pipe or socket behind `w` from a background thread, and calls `on_close` as soon
+/// as its reader goes away.
+///
+/// Programs that write rarely, like slow aggregators, otherwise only learn of a closed
+/// downstream at their next write. Terminating right away takes a closure like
+/// `|| pipecheck::exit_for_broken_pipe()`. The watcher does nothing for other kinds of output,
+/// which have no reader to lose.
+///
+/// The watcher runs until the returned [`ClosureWatcher`] is stopped or dropped, and `w`'s
+/// descriptor must stay open until then.
+#[cfg(unix)]
+pub fn watch_for_closure<W, F>(w: &W, on_close: F) -> io::Result<ClosureWatcher>
+where
+    W: AsRawFd + ?Sized,
+    F: FnOnce() + Send + 'static,
+{
+    let fd = w.as_raw_fd();
+    match unix::stream_info(fd).kind {
+        StreamKind::Pipe | StreamKind::Socket => {}
+        _ => {
+            return Ok(ClosureWatcher {
+                stop: None,
+                thread: None,
+            })
+        }
+    }
+    // Closing the write end of this pipe wakes the watcher up to stop.
+    let (stop_read, stop_write) = unix::pipe()?;
+    let spawned = thread::Builder::new()
+        .name("pipecheck watcher".to_owned())
+        .spawn(move || {
+            if unix::wait_for_closure(fd, stop_read) {
+                on_close();
+            }
+            unix::close(stop_read);
+        });
+    match spawned {
+        Ok(thread) => Ok(ClosureWatcher {
+            stop: Some(stop_write),
+            thread: Some(thread),
+        }),
+        Err(err) => {
+            unix::close(stop_read);
+            unix::close(stop_write);
+            Err(err)
+        }
+    }
+}
+
+/// A handle to the background thread of [`watch_for_closure`].
+///
+/// Dropping the handle stops the watcher, like [`ClosureWatcher::stop`], so programs that watch
+/// for the life of the process need to hold on to it.
+#[cfg(unix)]
+#[must_use = "dropping a ClosureWatcher stops it right away"]
+#[derive(Debug)]
+pub struct ClosureWatcher {
+    stop: Option<RawFd>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+#[cfg(unix)]
+impl ClosureWatcher {
+    /// Stops the watcher, and waits for its thread to exit.
+    ///
+    /// The callback doesn't run after this returns, but may already be running (and get waited
+    /// for) if the reader went away first.
+    pub fn stop(self) {
+        drop(self);
+    }
+
+    /// Waits for the watcher to finish, which happens once the reader goes away and the callback
+    /// returns, and passes on any panic from the callback.
+    ///
+    /// This returns right away for output that the watcher had no reader to watch for.
+    pub fn join(mut self) -> thread::Result<()> {
+        match self.thread.take() {
+            Some(thread) => thread.join(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ClosureWatcher {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            unix::close(stop);
+        }
+        if let Some(thread) = self.thread.take() {
+            // Like a scoped thread, we have nowhere to report a panic from here.
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Returns whether `err` is a broken pipe, exactly as a [`Writer`] classifies it.
+///
+/// Besides the [`BrokenPipe`](io::ErrorKind::BrokenPipe) kind, this recognizes the platform's
+/// broken pipe error code under any kind, and a broken pipe nested anywhere in the error's payload
+/// and its chain of sources.
+pub fn is_broken_pipe(err: &io::Error) -> bool {
+    is_broken_pipe_or(err, &[])
+}
+
+/// Returns whether any [`io::Error`] in the chain of sources starting at `err` is a broken pipe,
+/// as classified by [`is_broken_pipe`].
+///
+/// This suits the error types of other crates that wrap I/O errors, which a `Writer` never sees.
+pub fn error_chain_has_broken_pipe(err: &(dyn Error + 'static)) -> bool {
+    error_chain_has_broken_pipe_or(err, &[])
+}
+
+fn is_broken_pipe_or(err: &io::Error, also: &[io::ErrorKind]) -> bool {
+    // Encoders, TLS streams, and similar adapters sometimes re-wrap a broken pipe in an error of
+    // another kind, so we look through the payload too. Note that io::Error's own `source`
+    // skips the payload itself and returns the payload's source, hence `get_ref`.
+    err.kind() == io::ErrorKind::BrokenPipe
+        || also.contains(&err.kind())
+        || err.raw_os_error().map_or(false, is_broken_pipe_code)
+        || err
+            .get_ref()
+            .map_or(false, |inner| error_chain_has_broken_pipe_or(inner, also))
+}
+
+fn is_broken_pipe_code(code: i32) -> bool {
+    // Some wrappers and older platforms report the right OS error with the wrong kind.
+    #[cfg(unix)]
+    {
+        code == libc::EPIPE
+    }
+    #[cfg(windows)]
+    {
+        // ERROR_BROKEN_PIPE and ERROR_NO_DATA respectively. The latter commonly comes from
+        // writes to a pipe whose reader closed, and std has mapped it inconsistently over time.
+        code == 109 || code == 232
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = code;
+        false
+    }
+}
+
+fn error_chain_has_broken_pipe_or(mut err: &(dyn Error + 'static), also: &[io::ErrorKind]) -> bool {
+    loop {
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            return is_broken_pipe_or(err, also);
+        }
+        match err.source() {
+            Some(source) => err = source,
+            None => return false,
+        }
+    }
+}
+
+/// Terminates the process the way a default [`Writer`] does on a broken pipe.
+///
+/// This attempts termination by SIGPIPE, and otherwise falls back to a plain exit with code 1.
+/// Call it from an error handler that has already decided an error is a broken pipe.
+pub fn exit_for_broken_pipe() -> ! {
+    let config = Config::default();
+    terminate(config.signal, config.exit_code)
+}
+
+/// Flushes every writer passed to [`register_flush`] when the process exits normally, by returning
+/// from `main` or calling [`std::process::exit`] from anywhere (even deep in a library).
+///
+/// Broken pipes in these flushes are swallowed rather than acted on, since the process is already
+/// exiting. Calling this again after it succeeds has no further effect.
+#[cfg(unix)]
+pub fn flush_at_exit() -> io::Result<()> {
+    install_exit_handler()?;
+    FLUSH_AT_EXIT.store(true, Ordering::Release);
+    Ok(())
+}
+
+// Set once the exit handler starts running. A Writer's usual termination from there could call
+// exit a second time, which is undefined behavior.
+static EXITING: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+static FLUSH_AT_EXIT: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+fn install_exit_handler() -> io::Result<()> {
+    static INIT: Once = Once::new();
+    static FAILED: AtomicBool = AtomicBool::new(false);
+    INIT.call_once(|| {
+        if !unix::at_exit(run_at_exit) {
+            FAILED.store(true, Ordering::Release);
+        }
+    });
+    if FAILED.load(Ordering::Acquire) {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "failed to register an exit handler",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+extern "C" fn run_at_exit() {
+    EXITING.store(true, Ordering::Release);
+    // Unwinding out of an extern "C" function would abort the process, or worse on older Rust.
+    let _ = panic::catch_unwind(|| {
+        if FLUSH_AT_EXIT.load(Ordering::Acquire) {
+            flush_registered();
+        }
+        report_totals();
+    });
+}
+
+/// Flushes every writer passed to [`register_flush`], then exits the process with `code`.
 ///
-/// When any call to its underlying writer returns a [`BrokenPipe`](io::ErrorKind::BrokenPipe)
-/// error, a `Writer` terminates the current process with a SIGPIPE signal, or falls back to a
-/// plain exit with code 1.
-pub struct Writer<W>(W)
-where
-    W: Write;
+/// CLIs that exit early from deep in a call stack can use this in place of
+/// [`std::process::exit`] to keep buffered output from getting lost. Flush errors are ignored,
+/// except that a registered [`Writer`] hitting a broken pipe terminates the process as it's
+/// configured to, just like any other write through it.
+pub fn exit(code: i32) -> ! {
+    flush_registered();
+    std::process::exit(code)
+}
 
-impl<W> Writer<W>
+/// Terminates the process like [`exit_for_broken_pipe`] if `result` is a broken pipe, as
+/// classified by [`is_broken_pipe`], and otherwise returns it unchanged.
+///
+/// This applies a default [`Writer`]'s behavior to a single call, such as one into a library
+/// that does its own writing.
+pub fn check<T>(result: io::Result<T>) -> io::Result<T> {
+    match result {
+        Err(ref err) if is_broken_pipe(err) => exit_for_broken_pipe(),
+        _ => result,
+    }
+}
+
+fn terminate(signal: Option<i32>, exit_code: i32) -> ! {
+    flush_registered();
+
+    #[cfg(unix)]
+    {
+        if let Some(signal) = signal {
+            let _ = unix::try_terminating_by_signal(signal);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = signal;
+
+    // Outside of Unix, or in other cases where termination by a signal fails,
+    // we fall back to a plain exit (by default with the most generic code).
+    std::process::exit(exit_code);
+}
+
+/// Runs `f`, turning an [`Action::Unwind`] from any writer into that writer's usual termination.
+///
+/// Call this at the top of `main`, and of any other thread whose writers may unwind. Other panics
+/// propagate unchanged.
+pub fn catch_broken_pipe<F, R>(f: F) -> R
 where
-    W: Write,
+    F: FnOnce() -> R,
 {
-    pub fn new(w: W) -> Writer<W> {
-        Writer(w)
+    // Unwind safety doesn't matter here, as nothing observes the unwound state before
+    // the process terminates or the original panic resumes.
+    match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(payload) => match payload.downcast::<BrokenPipePanic>() {
+            Ok(broken) => terminate(broken.signal, broken.exit_code),
+            Err(payload) => panic::resume_unwind(payload),
+        },
     }
 }
 
-impl<W> Write for Writer<W>
+/// The panic payload of an [`Action::Unwind`], which [`catch_broken_pipe`] looks for.
+#[derive(Debug)]
+pub struct BrokenPipePanic {
+    signal: Option<i32>,
+    exit_code: i32,
+}
+
+/// Runs `f`, turning a [`BrokenPipeExit`] that it returns into the originating writer's usual
+/// termination.
+///
+/// Wrap the body of `main` with this when writers use [`Action::Defer`]. Any other result is
+/// returned unchanged. The `BrokenPipeExit` may be nested anywhere in the error's chain of sources
+/// when `E` is an [`io::Error`] or a boxed [`Error`] trait object (with or without `Send` and
+/// `Sync`); other error types are only checked for being a `BrokenPipeExit` themselves, so convert
+/// them to one of the above first.
+pub fn run<F, T, E>(f: F) -> Result<T, E>
 where
-    W: Write,
+    F: FnOnce() -> Result<T, E>,
+    E: 'static,
 {
-    // Rust 1.0.0 includes the following methods.
+    let result = f();
+    if let Err(ref err) = result {
+        if let Some(exit) = find_broken_pipe_exit(err) {
+            terminate(exit.signal, exit.exit_code);
+        }
+    }
+    result
+}
 
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        check_for_broken_pipe(self.0.write(buf))
+fn find_broken_pipe_exit(err: &dyn Any) -> Option<&BrokenPipeExit> {
+    // Without specialization, downcasting is the only way to see into the usual main error types
+    // while still accepting any error type.
+    let mut err: &(dyn Error + 'static) = if let Some(err) = err.downcast_ref::<BrokenPipeExit>() {
+        return Some(err);
+    } else if let Some(err) = err.downcast_ref::<io::Error>() {
+        err
+    } else if let Some(err) = err.downcast_ref::<Box<dyn Error + Send + Sync>>() {
+        &**err
+    } else if let Some(err) = err.downcast_ref::<Box<dyn Error + Send>>() {
+        &**err
+    } else if let Some(err) = err.downcast_ref::<Box<dyn Error>>() {
+        &**err
+    } else {
+        return None;
+    };
+    loop {
+        if let Some(exit) = err.downcast_ref::<BrokenPipeExit>() {
+            return Some(exit);
+        }
+        // As in error_chain_has_broken_pipe_or, io::Error hides its payload from `source`.
+        err = match err.downcast_ref::<io::Error>() {
+            Some(err) => err.get_ref()?,
+            None => err.source()?,
+        };
     }
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        check_for_broken_pipe(self.0.flush())
+/// The error payload of an [`Action::Defer`], which [`run`] looks for.
+///
+/// Writers return this inside an [`io::Error`] of kind [`BrokenPipe`](io::ErrorKind::BrokenPipe).
+/// Its source is the error that the underlying writer reported.
+#[derive(Debug)]
+pub struct BrokenPipeExit {
+    error: io::Error,
+    signal: Option<i32>,
+    exit_code: i32,
+}
+
+impl fmt::Display for BrokenPipeExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "exiting after broken pipe: {}", self.error)
     }
+}
 
-    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
-        check_for_broken_pipe(self.0.write_all(buf))
+impl Error for BrokenPipeExit {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
     }
+}
 
-    fn write_fmt(&mut self, fmt: std::fmt::Arguments<'_>) -> io::Result<()> {
-        check_for_broken_pipe(self.0.write_fmt(fmt))
+// Names the stream behind an error that a hook handed back to the caller, since a bare "Broken
+// pipe" from deep in a call stack doesn't say which stream broke.
+#[derive(Debug)]
+struct WriteContext {
+    name: String,
+    error: io::Error,
+}
+
+impl fmt::Display for WriteContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} while writing to {}", self.error, self.name)
     }
+}
 
-    // Rust 1.36.0 stabilizes write_vectored.
+impl Error for WriteContext {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
 
-    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
-        check_for_broken_pipe(self.0.write_vectored(bufs))
+/// Restores the default action for SIGPIPE process-wide, so any write to a broken pipe terminates
+/// the process, with or without a [`Writer`].
+///
+/// This is the classic alternative to wrapping writers. It also applies to writes from every
+/// library in the process and to child processes that inherit the disposition, which is why
+/// Rust ignores SIGPIPE by default. Outside of Unix, this does nothing.
+pub fn install_default_sigpipe() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        unix::set_signal_action(libc::SIGPIPE, libc::SIG_DFL).map(|_| ())
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(())
     }
 }
 
-fn check_for_broken_pipe<T>(result: io::Result<T>) -> io::Result<T> {
-    match result {
-        Err(ref err) if err.kind() == io::ErrorKind::BrokenPipe => exit_for_broken_pipe(),
-        result => result,
+/// Ignores SIGPIPE process-wide, as the Rust runtime arranges before `main`, undoing
+/// [`install_default_sigpipe`]. Outside of Unix, this does nothing.
+pub fn ignore_sigpipe() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        unix::set_signal_action(libc::SIGPIPE, libc::SIG_IGN).map(|_| ())
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(())
+    }
+}
+
+/// A guard that restores the default action for SIGPIPE while it lives, and puts the previous
+/// disposition back when dropped.
+///
+/// This suits a single region of a program, like a bulk dump subcommand, that should die
+/// Unix-style while the rest keeps Rust's usual behavior. The disposition is still process-wide,
+/// so it affects other threads during the region, and nested guards must drop in reverse order.
+/// Outside of Unix, this does nothing.
+pub struct DefaultSigpipeGuard {
+    #[cfg(unix)]
+    old_action: libc::sigaction,
+}
+
+impl DefaultSigpipeGuard {
+    /// Restores the default action for SIGPIPE until the returned guard is dropped.
+    pub fn new() -> io::Result<DefaultSigpipeGuard> {
+        Ok(DefaultSigpipeGuard {
+            #[cfg(unix)]
+            old_action: unix::set_signal_action(libc::SIGPIPE, libc::SIG_DFL)?,
+        })
+    }
+}
+
+impl fmt::Debug for DefaultSigpipeGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DefaultSigpipeGuard").finish()
+    }
+}
+
+impl Drop for DefaultSigpipeGuard {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        unix::restore_signal_action(libc::SIGPIPE, &self.old_action);
+    }
+}
+
+/// Returns the process-wide SIGPIPE disposition, without changing it.
+///
+/// This lets applications check whether something else in the process installed a SIGPIPE
+/// handler before relying on termination by SIGPIPE. Outside of Unix, it is always
+/// [`SigpipeDisposition::Unknown`].
+pub fn sigpipe_disposition() -> SigpipeDisposition {
+    #[cfg(unix)]
+    {
+        unix::sigpipe_disposition()
+    }
+    #[cfg(not(unix))]
+    {
+        SigpipeDisposition::Unknown
+    }
+}
+
+/// Returns what the file descriptor `fd` refers to, classified exactly as [`diagnose`] and
+/// [`watch_for_closure`] do internally.
+///
+/// This lets applications build their own policies on the same classification, e.g. to only
+/// wrap stdout in a [`Writer`] when it's a pipe or socket. An invalid descriptor is reported as
+/// [`StreamKind::Closed`].
+#[cfg(unix)]
+pub fn stream_info(fd: RawFd) -> StreamInfo {
+    unix::stream_info(fd)
+}
+
+/// Returns a snapshot of the process state that affects broken pipe handling.
+///
+/// The result's `Display` implementation prints a short report suitable for pasting into a bug
+/// report about a program that doesn't exit when piped into `head`. Outside of Unix, most of the
+/// report is unknown.
+pub fn diagnose() -> Diagnosis {
+    #[cfg(unix)]
+    {
+        Diagnosis {
+            sigpipe_disposition: sigpipe_disposition(),
+            sigpipe_blocked: unix::sigpipe_blocked(),
+            stdout: unix::stream_info(libc::STDOUT_FILENO),
+            stderr: unix::stream_info(libc::STDERR_FILENO),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let unknown = StreamInfo {
+            kind: StreamKind::Unknown,
+            pipe_capacity: None,
+        };
+        Diagnosis {
+            sigpipe_disposition: SigpipeDisposition::Unknown,
+            sigpipe_blocked: None,
+            stdout: unknown.clone(),
+            stderr: unknown,
+        }
+    }
+}
+
+/// The process state reported by [`diagnose`].
+#[derive(Clone, Debug)]
+pub struct Diagnosis {
+    sigpipe_disposition: SigpipeDisposition,
+    sigpipe_blocked: Option<bool>,
+    stdout: StreamInfo,
+    stderr: StreamInfo,
+}
+
+impl Diagnosis {
+    /// Returns the process-wide SIGPIPE disposition.
+    pub fn sigpipe_disposition(&self) -> SigpipeDisposition {
+        self.sigpipe_disposition
+    }
+
+    /// Returns whether SIGPIPE is blocked in the calling thread's signal mask, if known.
+    pub fn sigpipe_blocked(&self) -> Option<bool> {
+        self.sigpipe_blocked
+    }
+
+    /// Returns what the standard output stream refers to.
+    pub fn stdout(&self) -> &StreamInfo {
+        &self.stdout
+    }
+
+    /// Returns what the standard error stream refers to.
+    pub fn stderr(&self) -> &StreamInfo {
+        &self.stderr
+    }
+}
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "SIGPIPE disposition: {}", self.sigpipe_disposition)?;
+        match self.sigpipe_blocked {
+            Some(true) => writeln!(f, "SIGPIPE blocked: yes")?,
+            Some(false) => writeln!(f, "SIGPIPE blocked: no")?,
+            None => writeln!(f, "SIGPIPE blocked: unknown")?,
+        }
+        writeln!(f, "stdout: {}", self.stdout)?;
+        write!(f, "stderr: {}", self.stderr)
+    }
+}
+
+/// The process-wide action for SIGPIPE.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SigpipeDisposition {
+    /// The default action, which terminates the process.
+    Default,
+    /// SIGPIPE is ignored, as the Rust runtime arranges before `main` by default.
+    Ignored,
+    /// A handler function is installed.
+    CustomHandler,
+    /// The disposition could not be determined, e.g. outside of Unix.
+    Unknown,
+}
+
+impl fmt::Display for SigpipeDisposition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match *self {
+            SigpipeDisposition::Default => "default",
+            SigpipeDisposition::Ignored => "ignored",
+            SigpipeDisposition::CustomHandler => "custom handler",
+            SigpipeDisposition::Unknown => "unknown",
+        })
+    }
+}
+
+/// What a standard stream or other file descriptor refers to, as reported by [`diagnose`] and
+/// [`stream_info`].
+#[derive(Clone, Debug)]
+pub struct StreamInfo {
+    kind: StreamKind,
+    pipe_capacity: Option<usize>,
+}
+
+impl StreamInfo {
+    /// Returns the kind of file the stream refers to.
+    pub fn kind(&self) -> StreamKind {
+        self.kind
+    }
+
+    /// Returns the capacity of the pipe in bytes, if the stream is a pipe and the platform can
+    /// report it (currently Linux and Android).
+    pub fn pipe_capacity(&self) -> Option<usize> {
+        self.pipe_capacity
+    }
+}
+
+impl fmt::Display for StreamInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            StreamKind::Pipe => "pipe",
+            StreamKind::Socket => "socket",
+            StreamKind::Terminal => "terminal",
+            StreamKind::File => "regular file",
+            StreamKind::CharDevice => "character device",
+            StreamKind::Closed => "closed",
+            StreamKind::Unknown => "unknown",
+        };
+        match self.pipe_capacity {
+            Some(capacity) => write!(f, "{} (capacity {} bytes)", kind, capacity),
+            None => f.write_str(kind),
+        }
     }
 }
 
-fn exit_for_broken_pipe() -> ! {
+/// The kind of file a standard stream refers to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamKind {
+    Pipe,
+    Socket,
+    Terminal,
+    File,
+    /// A character device other than a terminal, like `/dev/null`.
+    CharDevice,
+    /// The file descriptor is not open.
+    Closed,
+    Unknown,
+}
+
+/// Checks how a [`Writer`] would terminate the current process by default.
+///
+/// On Unix, this runs the termination sequence in a forked child process that inherits the
+/// calling thread's signal mask and the process-wide SIGPIPE disposition, and reports how that
+/// child ended. It also accounts for PID 1 in a Linux PID namespace, which a child can't
+/// reproduce. Elsewhere, the result is always [`Termination::FallbackExit`].
+///
+/// This only covers the default behavior; hooks returning other [`Action`]s aren't consulted.
+pub fn self_test() -> io::Result<Termination> {
     #[cfg(unix)]
-    let _ = unix::try_terminating_by_sigpipe();
+    {
+        unix::self_test()
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(Termination::FallbackExit)
+    }
+}
+
+/// The way a [`Writer`] terminates the process by default, as reported by [`self_test`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Termination {
+    /// The process is killed by SIGPIPE.
+    Sigpipe,
+    /// The process falls back to a plain exit with code 1.
+    FallbackExit,
+}
+
+/// Utilities for exercising broken pipe handling in tests, available with the `testing` feature.
+///
+/// Enable the feature only for tests, so that release builds carry none of the fault injection
+/// state. With Cargo's version 2 feature resolver (`resolver = "2"`, the default from edition
+/// 2021), enabling it in `[dev-dependencies]` does that. The version 1 resolver, the default for
+/// earlier editions and the only one before Rust 1.51, also applies features of dev-dependencies
+/// to normal builds, so the check ends up in release builds too.
+#[cfg(any(test, feature = "testing"))]
+pub mod testing {
+    use std::fmt;
+    use std::io::{self, BufRead, BufReader, Read, Write};
+    use std::process::{Command, ExitStatus, Stdio};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    static FAULT_COUNTDOWN: AtomicUsize = AtomicUsize::new(0);
+
+    /// Makes the `n`th write (counting from 1) across every [`Writer`](super::Writer) in the
+    /// process fail with a simulated broken pipe error, or disables injection if `n` is 0.
+    ///
+    /// Each call restarts the count, and only one write is failed per call. The simulated error
+    /// goes through the same hooks and termination as a real one, and the failed write never
+    /// reaches the underlying writer. To fuzz truncation deterministically, derive `n` from
+    /// a seed.
+    pub fn inject_broken_pipe_at(n: usize) {
+        FAULT_COUNTDOWN.store(n, Ordering::SeqCst);
+    }
+
+    pub(super) fn injected_fault() -> io::Result<()> {
+        let mut n = FAULT_COUNTDOWN.load(Ordering::Relaxed);
+        while n != 0 {
+            match FAULT_COUNTDOWN.compare_exchange_weak(
+                n,
+                n - 1,
+                Ordering::SeqCst,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) if n == 1 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "injected by pipecheck::testing",
+                    ))
+                }
+                Ok(_) => break,
+                Err(current) => n = current,
+            }
+        }
+        Ok(())
+    }
+
+    /// A writer that simulates a slow downstream for backpressure and deadline tests.
+    ///
+    /// Each write passes at most `max_per_write` bytes (but at least 1) to the underlying
+    /// writer, after sleeping for `delay` if it is non-zero.
+    pub struct ThrottledWriter<W>
+    where
+        W: Write,
+    {
+        inner: W,
+        max_per_write: usize,
+        delay: Duration,
+    }
+
+    impl<W> ThrottledWriter<W>
+    where
+        W: Write,
+    {
+        pub fn new(w: W, max_per_write: usize, delay: Duration) -> ThrottledWriter<W> {
+            ThrottledWriter {
+                inner: w,
+                max_per_write: max_per_write.max(1),
+                delay,
+            }
+        }
+    }
+
+    impl<W> fmt::Debug for ThrottledWriter<W>
+    where
+        W: Write,
+    {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("ThrottledWriter")
+                .field("max_per_write", &self.max_per_write)
+                .field("delay", &self.delay)
+                .finish()
+        }
+    }
+
+    impl<W> Write for ThrottledWriter<W>
+    where
+        W: Write,
+    {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.delay > Duration::from_secs(0) {
+                thread::sleep(self.delay);
+            }
+            let len = buf.len().min(self.max_per_write);
+            self.inner.write(&buf[..len])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    /// Runs `command` like `command | head -n <lines>` and waits for it to exit.
+    ///
+    /// The child's standard output is piped into a reader that keeps the first `lines` lines,
+    /// then closes its end of the pipe. Returns the child's exit status along with the output
+    /// read before closing.
+    pub fn run_with_head(command: &mut Command, lines: usize) -> io::Result<(ExitStatus, Vec<u8>)> {
+        let mut child = command.stdout(Stdio::piped()).spawn()?;
+        let mut output = Vec::new();
+        if let Some(stdout) = child.stdout.take() {
+            let mut reader = BufReader::new(stdout);
+            for _ in 0..lines {
+                if reader.read_until(b'\n', &mut output)? == 0 {
+                    break;
+                }
+            }
+        }
+        let status = child.wait()?;
+        Ok((status, output))
+    }
+
+    /// Runs `command` like `yes <line> | command` and waits for it to exit.
+    ///
+    /// A background thread writes `line` to the child's standard input over and over until the
+    /// child closes it or exits, while the child's standard output is collected in full. Returns
+    /// the child's exit status along with its output.
+    ///
+    /// The feeding thread stops at the first write error, which relies on SIGPIPE being ignored
+    /// in the calling process, as it is by default in Rust programs and test harnesses.
+    pub fn run_with_yes(command: &mut Command, line: &[u8]) -> io::Result<(ExitStatus, Vec<u8>)> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        // Dropping stdin right away for an empty line gives the child an empty input.
+        let stdin = child.stdin.take().filter(|_| !line.is_empty());
+        let feeder = stdin.map(|mut stdin| {
+            let line = line.to_owned();
+            thread::spawn(move || while stdin.write_all(&line).is_ok() {})
+        });
+        let mut output = Vec::new();
+        if let Some(mut stdout) = child.stdout.take() {
+            stdout.read_to_end(&mut output)?;
+        }
+        let status = child.wait()?;
+        if let Some(feeder) = feeder {
+            let _ = feeder.join();
+        }
+        Ok((status, output))
+    }
 
-    // Outside of Unix, or in other cases where termination by SIGPIPE fails,
-    // we fall back to a plain exit with the most generic code.
-    std::process::exit(1);
+    /// Returns whether `status` is what a [`Writer`](super::Writer) produces by default after
+    /// a broken pipe: termination by SIGPIPE on Unix, or exit code 1 elsewhere.
+    pub fn exited_by_broken_pipe(status: &ExitStatus) -> bool {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            status.signal() == Some(libc::SIGPIPE)
+        }
+        #[cfg(not(unix))]
+        {
+            status.code() == Some(1)
+        }
+    }
 }
 
 #[cfg(unix)]
 mod unix {
+    use std::cmp;
     use std::convert::Infallible;
+    use std::io;
     use std::mem::MaybeUninit;
     use std::ptr;
+    use std::time::{Duration, Instant};
 
-    pub fn try_terminating_by_sigpipe() -> Result<Infallible, ()> {
-        // Start by unblocking SIGPIPE. Doing this thread-local operation first may shorten
+    use super::{SigpipeDisposition, StreamInfo, StreamKind, Termination};
+
+    pub fn try_terminating_by_signal(signal: libc::c_int) -> Result<Infallible, ()> {
+        // Start by unblocking the signal. Doing this thread-local operation first may shorten
         // the race window between the process-wide action reset and the raise of the signal.
-        unblock_sigpipe()?;
+        let old_mask = unblock_signal(signal)?;
 
         // Reset the process-wide action; see the upstream pipecheck crate for caveats.
-        reset_sigpipe_action()?;
+        let old_action = match set_signal_action(signal, libc::SIG_DFL) {
+            Ok(old_action) => old_action,
+            Err(_) => {
+                restore_signal_mask(&old_mask);
+                return Err(());
+            }
+        };
 
-        // SAFETY: We know SIGPIPE is a valid signal value, and POSIX.1 requires this
-        // to be reentrant in multi-threaded programs. This should terminate the program,
+        // SAFETY: The successful sigaction above means this is a valid signal value, and POSIX.1
+        // requires this to be reentrant in multi-threaded programs. This should terminate the program,
         // but might not due to behavioral caveats documented in the upstream pipecheck crate.
-        unsafe { libc::raise(libc::SIGPIPE) };
+        //
+        // POSIX.1 also defines raise in multi-threaded programs as pthread_kill(pthread_self(),
+        // sig), so the signal is always delivered to this thread (whose mask we just unblocked)
+        // before raise returns. Calling pthread_kill directly would change nothing.
+        unsafe { libc::raise(signal) };
 
-        // If any of that failed, we fall back to a plain exit.
+        // If we're still alive, put the signal state back the way we found it, so that neither
+        // the fallback exit nor anything else that keeps running sees our changes.
+        restore_signal_action(signal, &old_action);
+        restore_signal_mask(&old_mask);
         Err(())
     }
 
-    fn unblock_sigpipe() -> Result<(), ()> {
+    fn unblock_signal(signal: libc::c_int) -> Result<libc::sigset_t, ()> {
         // SAFETY: Per sigsetops(3), `sigemptyset` is a valid way to initialize a signal set,
         // and it's done before any other use.
-        let sigpipe_set: libc::sigset_t = unsafe {
+        let signal_set: libc::sigset_t = unsafe {
             let mut set = MaybeUninit::uninit();
             libc::sigemptyset(set.as_mut_ptr());
-            libc::sigaddset(set.as_mut_ptr(), libc::SIGPIPE);
+            if libc::sigaddset(set.as_mut_ptr(), signal) != 0 {
+                return Err(());
+            }
             set.assume_init()
         };
 
-        // SAFETY: `set` is initialized above, and `oset` is permitted to be null.
+        // SAFETY: `set` is initialized above, and `oset` is valid for writes.
         // `pthread_sigmask` is explicitly specified by POSIX.1 for use in multithreaded programs
         // (unlike `sigprocmask`).
         unsafe {
-            match libc::pthread_sigmask(libc::SIG_UNBLOCK, &sigpipe_set, ptr::null_mut()) {
-                0 => Ok(()),
+            let mut old_mask = MaybeUninit::uninit();
+            match libc::pthread_sigmask(libc::SIG_UNBLOCK, &signal_set, old_mask.as_mut_ptr()) {
+                0 => Ok(old_mask.assume_init()),
                 _ => Err(()), // In theory, this can only be hit if `how` is invalid.
             }
         }
     }
 
-    fn reset_sigpipe_action() -> Result<(), ()> {
+    fn restore_signal_mask(old_mask: &libc::sigset_t) {
+        // SAFETY: `old_mask` was filled in by a successful `pthread_sigmask` call,
+        // and `oset` is permitted to be null.
+        unsafe { libc::pthread_sigmask(libc::SIG_SETMASK, old_mask, ptr::null_mut()) };
+    }
+
+    pub fn set_signal_action(
+        signal: libc::c_int,
+        handler: libc::sighandler_t,
+    ) -> io::Result<libc::sigaction> {
         // SAFETY: sigaction is a C struct, so zeroed() is a valid type-level initialization.
         // Rust's usual struct initializer syntax is a bad idea,
         // since certain platforms might have extra fields we aren't ready for.
         let mut act: libc::sigaction = unsafe { MaybeUninit::zeroed().assume_init() };
-        act.sa_sigaction = libc::SIG_DFL;
+        act.sa_sigaction = handler;
 
-        // SAFETY: `act` is initialized above, and `oact` is permitted to be null.
+        // SAFETY: `act` is initialized above, and `oact` is valid for writes.
         // POSIX.1 requires this to be reentrant in multi-threaded programs.
         unsafe {
-            match libc::sigaction(libc::SIGPIPE, &act, ptr::null_mut()) {
-                0 => Ok(()),
-                _ => Err(()),
+            let mut old_act = MaybeUninit::zeroed();
+            match libc::sigaction(signal, &act, old_act.as_mut_ptr()) {
+                0 => Ok(old_act.assume_init()),
+                _ => Err(io::Error::last_os_error()),
+            }
+        }
+    }
+
+    pub fn restore_signal_action(signal: libc::c_int, old_act: &libc::sigaction) {
+        // SAFETY: `old_act` was filled in by a successful `sigaction` call,
+        // and `oact` is permitted to be null.
+        unsafe { libc::sigaction(signal, old_act, ptr::null_mut()) };
+    }
+
+    pub fn self_test() -> io::Result<Termination> {
+        // The kernel never delivers default-action signals to PID 1 from within its own
+        // namespace, and a child process can't observe that.
+        // SAFETY: `getpid` is always safe to call.
+        if cfg!(any(target_os = "linux", target_os = "android")) && unsafe { libc::getpid() } == 1 {
+            return Ok(Termination::FallbackExit);
+        }
+
+        // SAFETY: The child only calls async-signal-safe functions before `_exit`,
+        // as required when forking a potentially multithreaded process.
+        let pid = match unsafe { libc::fork() } {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => unsafe {
+                let _ = try_terminating_by_signal(libc::SIGPIPE);
+                libc::_exit(1);
+            },
+            pid => pid,
+        };
+
+        let mut status = 0;
+        loop {
+            // SAFETY: `pid` is our own child, and `status` is valid for writes.
+            if unsafe { libc::waitpid(pid, &mut status, 0) } != -1 {
+                break;
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        if libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGPIPE {
+            Ok(Termination::Sigpipe)
+        } else {
+            Ok(Termination::FallbackExit)
+        }
+    }
+
+    pub fn sigpipe_disposition() -> SigpipeDisposition {
+        // SAFETY: A null `act` only queries the current action, and `oact` is valid for writes.
+        let act: libc::sigaction = unsafe {
+            let mut act = MaybeUninit::zeroed();
+            if libc::sigaction(libc::SIGPIPE, ptr::null(), act.as_mut_ptr()) != 0 {
+                return SigpipeDisposition::Unknown;
+            }
+            act.assume_init()
+        };
+        match act.sa_sigaction {
+            libc::SIG_DFL => SigpipeDisposition::Default,
+            libc::SIG_IGN => SigpipeDisposition::Ignored,
+            _ => SigpipeDisposition::CustomHandler,
+        }
+    }
+
+    pub fn sigpipe_blocked() -> Option<bool> {
+        // SAFETY: A null `set` only queries the current mask, and `oset` is valid for writes.
+        // `sigismember` is only called after the mask is initialized.
+        unsafe {
+            let mut mask = MaybeUninit::uninit();
+            if libc::pthread_sigmask(libc::SIG_BLOCK, ptr::null(), mask.as_mut_ptr()) != 0 {
+                return None;
+            }
+            match libc::sigismember(mask.as_ptr(), libc::SIGPIPE) {
+                1 => Some(true),
+                0 => Some(false),
+                _ => None,
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[derive(Clone, Copy)]
+    pub enum Offload {
+        Splice,
+        CopyFileRange,
+        Sendfile,
+    }
+
+    #[cfg(target_os = "linux")]
+    impl Offload {
+        pub fn choose(in_fd: libc::c_int, out_fd: libc::c_int) -> Option<Offload> {
+            match (stream_info(in_fd).kind, stream_info(out_fd).kind) {
+                (StreamKind::Pipe, _) | (_, StreamKind::Pipe) => Some(Offload::Splice),
+                (StreamKind::File, StreamKind::File) => Some(Offload::CopyFileRange),
+                (StreamKind::File, _) => Some(Offload::Sendfile),
+                _ => None,
+            }
+        }
+
+        pub fn fallback(self) -> Option<Offload> {
+            match self {
+                // Older kernels only support copy_file_range within a single filesystem.
+                Offload::CopyFileRange => Some(Offload::Sendfile),
+                Offload::Splice | Offload::Sendfile => None,
+            }
+        }
+
+        pub fn copy(self, in_fd: libc::c_int, out_fd: libc::c_int) -> io::Result<usize> {
+            const CHUNK: usize = 1 << 20;
+            // SAFETY: Null offsets make each call use and advance the descriptors' own file
+            // offsets, as read(2) and write(2) would. We call copy_file_range through syscall(2)
+            // since libc wrappers for it are newer than some of the systems we run on.
+            let n = unsafe {
+                match self {
+                    Offload::Splice => {
+                        libc::splice(in_fd, ptr::null_mut(), out_fd, ptr::null_mut(), CHUNK, 0)
+                    }
+                    Offload::CopyFileRange => libc::syscall(
+                        libc::SYS_copy_file_range,
+                        in_fd,
+                        ptr::null_mut::<libc::loff_t>(),
+                        out_fd,
+                        ptr::null_mut::<libc::loff_t>(),
+                        CHUNK,
+                        0 as libc::c_uint,
+                    ) as libc::ssize_t,
+                    Offload::Sendfile => libc::sendfile(out_fd, in_fd, ptr::null_mut(), CHUNK),
+                }
+            };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(n as usize)
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn is_unsupported_offload(err: &io::Error) -> bool {
+        // These cover descriptors that a given offload can't handle (including O_APPEND outputs
+        // and non-blocking ones), as opposed to failures that a plain copy would hit too.
+        match err.raw_os_error() {
+            Some(code) => [
+                libc::EINVAL,
+                libc::ENOSYS,
+                libc::EXDEV,
+                libc::EOPNOTSUPP,
+                libc::EBADF,
+                libc::EAGAIN,
+            ]
+            .contains(&code),
+            None => false,
+        }
+    }
+
+    pub fn write(fd: libc::c_int, buf: &[u8]) -> io::Result<usize> {
+        // SAFETY: `buf` is valid for reads of its whole length.
+        let n = unsafe { libc::write(fd, buf.as_ptr() as *const libc::c_void, buf.len()) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    pub fn writev(fd: libc::c_int, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        // POSIX only guarantees 16 buffers per call, and a short write is fine for the rest.
+        // SAFETY: `sysconf` accepts any name, and reports failure with -1.
+        let iov_max = match unsafe { libc::sysconf(libc::_SC_IOV_MAX) } {
+            max if max > 0 => max as usize,
+            _ => 16,
+        };
+        let count = cmp::min(bufs.len(), iov_max);
+        // SAFETY: IoSlice is guaranteed to be ABI compatible with iovec on Unix, and the first
+        // `count` elements of `bufs` are valid for reads.
+        let n = unsafe {
+            libc::writev(
+                fd,
+                bufs.as_ptr() as *const libc::iovec,
+                count as libc::c_int,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    pub fn wait_for_closure(fd: libc::c_int, stop: libc::c_int) -> bool {
+        // With no events requested, poll only wakes up for the error (pipes) or hangup (sockets)
+        // conditions that come with losing the reader, or for a descriptor that went invalid.
+        // The stop pipe becomes readable (hung up) once its write end is closed.
+        let mut pollfds = [
+            libc::pollfd {
+                fd,
+                events: 0,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: stop,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        loop {
+            // SAFETY: `pollfds` is valid for reads and writes as an array of two elements.
+            if unsafe { libc::poll(pollfds.as_mut_ptr(), 2, -1) } > 0 {
+                // A stop request wins over a closure seen at the same time.
+                return pollfds[1].revents == 0
+                    && pollfds[0].revents & (libc::POLLERR | libc::POLLHUP) != 0;
+            }
+            if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                return false;
+            }
+        }
+    }
+
+    pub fn pipe() -> io::Result<(libc::c_int, libc::c_int)> {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` is valid for writes as an array of two descriptors.
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for &fd in &fds {
+            // Child processes have no business with these. This can only fail for a bad
+            // descriptor, which a fresh pipe's aren't.
+            // SAFETY: F_SETFD takes an integer and touches no memory.
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+        Ok((fds[0], fds[1]))
+    }
+
+    pub fn close(fd: libc::c_int) {
+        // SAFETY: Callers only close descriptors that they own.
+        unsafe { libc::close(fd) };
+    }
+
+    pub fn at_exit(f: extern "C" fn()) -> bool {
+        // SAFETY: Any function pointer is fine to register, and `f` takes no arguments.
+        unsafe { libc::atexit(f) == 0 }
+    }
+
+    // POSIX only guarantees 512 bytes, and Linux (where a writable pipe has at least a page free)
+    // makes it a page.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub const PIPE_BUF: usize = libc::PIPE_BUF;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub const PIPE_BUF: usize = 512;
+
+    pub fn wait_writable(fd: libc::c_int, timeout: Option<Duration>) -> io::Result<()> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLOUT,
+            revents: 0,
+        };
+        loop {
+            let timeout_ms = match deadline {
+                None => -1,
+                Some(deadline) => {
+                    let now = Instant::now();
+                    let left = if deadline > now {
+                        deadline - now
+                    } else {
+                        Duration::from_secs(0)
+                    };
+                    // Round up, so we never wake just before the deadline and give up early.
+                    cmp::min(
+                        (left.as_micros() + 999) / 1000,
+                        libc::c_int::max_value() as u128,
+                    ) as libc::c_int
+                }
+            };
+            // SAFETY: `pollfd` is valid for reads and writes as a single-element array.
+            match unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } {
+                // An error or hangup will come out of the next write.
+                n if n > 0 => return Ok(()),
+                0 => return Err(io::Error::new(io::ErrorKind::TimedOut, "write timed out")),
+                _ => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn stream_info(fd: libc::c_int) -> StreamInfo {
+        // SAFETY: `buf` is valid for writes, and is only read after `fstat` succeeds.
+        let stat: libc::stat = unsafe {
+            let mut stat = MaybeUninit::zeroed();
+            if libc::fstat(fd, stat.as_mut_ptr()) != 0 {
+                let kind = match io::Error::last_os_error().raw_os_error() {
+                    Some(libc::EBADF) => StreamKind::Closed,
+                    _ => StreamKind::Unknown,
+                };
+                return StreamInfo {
+                    kind,
+                    pipe_capacity: None,
+                };
             }
+            stat.assume_init()
+        };
+
+        let kind = match stat.st_mode & libc::S_IFMT {
+            libc::S_IFIFO => StreamKind::Pipe,
+            libc::S_IFSOCK => StreamKind::Socket,
+            libc::S_IFREG => StreamKind::File,
+            // SAFETY: `isatty` accepts any file descriptor value.
+            libc::S_IFCHR if unsafe { libc::isatty(fd) } == 1 => StreamKind::Terminal,
+            libc::S_IFCHR => StreamKind::CharDevice,
+            _ => StreamKind::Unknown,
+        };
+        let pipe_capacity = match kind {
+            StreamKind::Pipe => pipe_capacity(fd),
+            _ => None,
+        };
+        StreamInfo {
+            kind,
+            pipe_capacity,
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn pipe_capacity(fd: libc::c_int) -> Option<usize> {
+        // SAFETY: F_GETPIPE_SZ takes no further arguments and fails cleanly for non-pipes.
+        match unsafe { libc::fcntl(fd, libc::F_GETPIPE_SZ) } {
+            -1 => None,
+            capacity => Some(capacity as usize),
         }
     }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn pipe_capacity(_fd: libc::c_int) -> Option<usize> {
+        None
+    }
 }
@@ -0,0 +1,343 @@
+//! Runs this binary in real pipelines to check how it exits.
+//!
+//! Without a harness, the same binary serves as the test driver and as the child process under
+//! test, picking a child mode from the environment.
+
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::process::{self, Command};
+use std::thread;
+
+use pipecheck::testing::{exited_by_broken_pipe, run_with_head, run_with_yes};
+use pipecheck::{Action, LimitWriter};
+
+const CHILD_MODE: &str = "PIPECHECK_TEST_CHILD";
+const MARKER: &str = "PIPECHECK_TEST_MARKER";
+
+// The same on every Unix, and the tests don't depend on libc for it.
+#[cfg(unix)]
+const SIGTERM: i32 = 15;
+
+fn main() {
+    if let Some(mode) = env::var_os(CHILD_MODE) {
+        match mode.to_str() {
+            Some("yes") => child_yes(),
+            Some("lines") => child_lines(),
+            Some("unwind") => child_unwind(),
+            #[cfg(unix)]
+            Some("copy") => child_copy(),
+            Some("limit") => child_limit(),
+            Some("defer") => child_defer(),
+            Some("discard") => child_discard(),
+            Some("sync-lines") => child_sync_lines(),
+            Some("ordered") => child_ordered(),
+            Some("exit-code") => child_exit_code(),
+            #[cfg(unix)]
+            Some("signal") => child_signal(),
+            Some("inject") => child_inject(),
+            _ => panic!("unknown child mode {:?}", mode),
+        }
+        return;
+    }
+
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut tests: Vec<(&str, fn())> = vec![
+        ("terminates_under_head", terminates_under_head),
+        ("reads_from_yes", reads_from_yes),
+        ("unwinds_before_terminating", unwinds_before_terminating),
+        ("stops_at_line_limit", stops_at_line_limit),
+        (
+            "defers_through_boxed_error_chain",
+            defers_through_boxed_error_chain,
+        ),
+        ("discards_after_broken_pipe", discards_after_broken_pipe),
+        (
+            "keeps_lines_whole_across_threads",
+            keeps_lines_whole_across_threads,
+        ),
+        (
+            "writes_ordered_chunks_in_sequence",
+            writes_ordered_chunks_in_sequence,
+        ),
+        ("exits_with_configured_code", exits_with_configured_code),
+        ("terminates_at_injected_fault", terminates_at_injected_fault),
+    ];
+    #[cfg(unix)]
+    tests.push(("copies_under_head", copies_under_head));
+    #[cfg(unix)]
+    tests.push((
+        "terminates_by_configured_signal",
+        terminates_by_configured_signal,
+    ));
+    for (name, test) in tests {
+        print!("test {} ... ", name);
+        let _ = io::stdout().flush();
+        test();
+        println!("ok");
+    }
+}
+
+fn child(mode: &str) -> Command {
+    let mut command = Command::new(env::current_exe().unwrap());
+    command.env(CHILD_MODE, mode);
+    command
+}
+
+fn marker_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("pipecheck-{}-{}", name, process::id()))
+}
+
+fn terminates_under_head() {
+    let (status, output) = run_with_head(&mut child("yes"), 1).unwrap();
+    assert!(exited_by_broken_pipe(&status), "{:?}", status);
+    assert_eq!(output, b"y\n");
+}
+
+fn child_yes() {
+    let mut stdout = pipecheck::stdout();
+    loop {
+        writeln!(stdout, "y").unwrap();
+    }
+}
+
+fn reads_from_yes() {
+    let (status, output) = run_with_yes(&mut child("lines"), b"n\n").unwrap();
+    assert!(status.success(), "{:?}", status);
+    assert_eq!(output, b"n\nn\nn\n");
+}
+
+fn child_lines() {
+    let stdin = io::stdin();
+    let mut stdout = pipecheck::stdout();
+    for line in stdin.lock().lines().take(3) {
+        writeln!(stdout, "{}", line.unwrap()).unwrap();
+    }
+}
+
+fn unwinds_before_terminating() {
+    let marker = marker_path("unwind");
+    let _ = fs::remove_file(&marker);
+    let (status, output) = run_with_head(child("unwind").env(MARKER, &marker), 1).unwrap();
+    assert!(exited_by_broken_pipe(&status), "{:?}", status);
+    assert_eq!(output, b"y\n");
+    assert!(marker.exists(), "drop guard did not run");
+    fs::remove_file(&marker).unwrap();
+}
+
+fn child_unwind() {
+    struct Guard;
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            fs::write(env::var_os(MARKER).unwrap(), b"dropped").unwrap();
+        }
+    }
+    pipecheck::catch_broken_pipe(|| {
+        let _guard = Guard;
+        let mut stdout = pipecheck::Writer::with_hook(io::stdout(), |_| Action::Unwind);
+        loop {
+            writeln!(stdout, "y").unwrap();
+        }
+    });
+}
+
+#[cfg(unix)]
+fn copies_under_head() {
+    let input = marker_path("copy");
+    let lines: String = (0..100_000).map(|i| format!("{}\n", i)).collect();
+    fs::write(&input, lines).unwrap();
+    let (status, output) = run_with_head(child("copy").env(MARKER, &input), 2).unwrap();
+    fs::remove_file(&input).unwrap();
+    assert!(exited_by_broken_pipe(&status), "{:?}", status);
+    assert_eq!(output, b"0\n1\n");
+}
+
+#[cfg(unix)]
+fn child_copy() {
+    let mut input = fs::File::open(env::var_os(MARKER).unwrap()).unwrap();
+    let mut stdout = pipecheck::Writer::new(pipecheck::RawFdWriter::stdout());
+    pipecheck::copy(&mut input, &mut stdout).unwrap();
+}
+
+fn stops_at_line_limit() {
+    let (status, output) = run_with_head(&mut child("limit"), 100).unwrap();
+    assert!(exited_by_broken_pipe(&status), "{:?}", status);
+    assert_eq!(output, b"y\ny\ny\n");
+}
+
+fn child_limit() {
+    let mut stdout = pipecheck::Writer::new(LimitWriter::lines(io::stdout(), 3));
+    loop {
+        // Partial lines must not count toward the limit.
+        stdout.write_all(b"y").unwrap();
+        stdout.write_all(b"\n").unwrap();
+    }
+}
+
+fn defers_through_boxed_error_chain() {
+    let (status, output) = run_with_head(&mut child("defer"), 1).unwrap();
+    assert_eq!(status.code(), Some(42), "{:?}", status);
+    assert_eq!(output, b"y\n");
+}
+
+fn child_defer() {
+    #[derive(Debug)]
+    struct Context(io::Error);
+    impl fmt::Display for Context {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("writing output")
+        }
+    }
+    impl Error for Context {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    let result = pipecheck::run(|| -> Result<(), Box<dyn Error>> {
+        let mut stdout = pipecheck::Writer::builder(io::stdout())
+            .sigpipe(false)
+            .exit_code(42)
+            .hook(|_| Action::Defer)
+            .build();
+        loop {
+            writeln!(stdout, "y").map_err(Context)?;
+        }
+    });
+    panic!("run returned {:?}", result);
+}
+
+fn discards_after_broken_pipe() {
+    let (status, output) = run_with_head(&mut child("discard"), 1).unwrap();
+    assert!(status.success(), "{:?}", status);
+    assert_eq!(output, b"y\n");
+}
+
+fn child_discard() {
+    let mut stdout = pipecheck::Writer::with_hook(io::stdout(), |_| Action::Discard);
+    while !stdout.pipe_broken() {
+        writeln!(stdout, "y").unwrap();
+    }
+    // Later writes keep reporting success, so the program can wind down normally.
+    writeln!(stdout, "discarded").unwrap();
+    stdout.flush().unwrap();
+}
+
+fn keeps_lines_whole_across_threads() {
+    let (status, output) = run_with_head(&mut child("sync-lines"), usize::max_value()).unwrap();
+    assert!(status.success(), "{:?}", status);
+    let output = String::from_utf8(output).unwrap();
+    let mut counts = [0; 4];
+    for line in output.lines() {
+        let (thread, rest) = line.split_at(1);
+        assert_eq!(
+            rest,
+            format!(":{}", "x".repeat(100)),
+            "torn line {:?}",
+            line
+        );
+        counts[thread.parse::<usize>().unwrap()] += 1;
+    }
+    assert_eq!(counts, [1000; 4]);
+}
+
+fn child_sync_lines() {
+    let stdout = pipecheck::SyncLineWriter::new(io::stdout());
+    let threads: Vec<_> = (0..4)
+        .map(|thread| {
+            let mut stdout = stdout.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    // Each line goes out in pieces that other threads could land between.
+                    write!(stdout, "{}:", thread).unwrap();
+                    stdout.write_all("x".repeat(50).as_bytes()).unwrap();
+                    stdout.write_all("x".repeat(50).as_bytes()).unwrap();
+                    stdout.write_all(b"\n").unwrap();
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+fn writes_ordered_chunks_in_sequence() {
+    let (status, output) = run_with_head(&mut child("ordered"), usize::max_value()).unwrap();
+    assert!(status.success(), "{:?}", status);
+    let expected: String = (0..100).map(|seq| format!("{}\n", seq)).collect();
+    assert_eq!(String::from_utf8(output).unwrap(), expected);
+}
+
+fn child_ordered() {
+    let sink = pipecheck::OrderedSink::new(pipecheck::stdout());
+    // Each worker submits its share of the chunks from last to first, so nearly every chunk has to
+    // wait for an earlier one.
+    let workers: Vec<_> = (0..4)
+        .map(|worker| {
+            let sink = sink.clone();
+            thread::spawn(move || {
+                for seq in (0..100).filter(|seq| seq % 4 == worker).rev() {
+                    sink.submit(seq, format!("{}\n", seq).into_bytes()).unwrap();
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    sink.flush().unwrap();
+    let err = sink.submit(0, b"again\n".to_vec()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+fn exits_with_configured_code() {
+    let (status, output) = run_with_head(&mut child("exit-code"), 1).unwrap();
+    assert_eq!(status.code(), Some(42), "{:?}", status);
+    assert_eq!(output, b"y\n");
+}
+
+fn child_exit_code() {
+    let mut stdout = pipecheck::Writer::builder(io::stdout())
+        .sigpipe(false)
+        .exit_code(42)
+        .build();
+    loop {
+        writeln!(stdout, "y").unwrap();
+    }
+}
+
+#[cfg(unix)]
+fn terminates_by_configured_signal() {
+    use std::os::unix::process::ExitStatusExt;
+    let (status, output) = run_with_head(&mut child("signal"), 1).unwrap();
+    assert_eq!(status.signal(), Some(SIGTERM), "{:?}", status);
+    assert_eq!(output, b"y\n");
+}
+
+#[cfg(unix)]
+fn child_signal() {
+    let mut stdout = pipecheck::Writer::builder(io::stdout())
+        .signal(SIGTERM)
+        .build();
+    loop {
+        writeln!(stdout, "y").unwrap();
+    }
+}
+
+fn terminates_at_injected_fault() {
+    let (status, output) = run_with_head(&mut child("inject"), 100).unwrap();
+    assert!(exited_by_broken_pipe(&status), "{:?}", status);
+    assert_eq!(output, b"1\n2\n");
+}
+
+fn child_inject() {
+    let mut stdout = pipecheck::stdout();
+    pipecheck::testing::inject_broken_pipe_at(3);
+    for i in 1.. {
+        writeln!(stdout, "{}", i).unwrap();
+    }
+}